@@ -1,49 +1,78 @@
 use {
-    crate::{download::MediaKind, utils::{default, Result}},
+    crate::{download::OutputFormat, utils::{default, Result}},
     serde::{Deserialize, Serialize},
-    std::{collections::HashMap, fs::File, io::ErrorKind::NotFound, ops::Deref},
+    std::{borrow::Cow, collections::HashMap, fs::File, io::ErrorKind::NotFound, ops::Deref},
     tokio::sync::{RwLock, RwLockReadGuard},
 };
 
-const CACHE_PATH: &str = concat!(env!("CACHE_DIR"), "tg_id_cache.json");
-
 #[derive(Deserialize, Serialize, Default)]
 struct Inner {
     /// Maps links to audio files to their Telegram IDs.
     tracks: HashMap<Box<str>, Box<str>>,
     /// Maps links to video files to their Telegram IDs.
     videos: HashMap<Box<str>, Box<str>>,
+    /// Maps links to files in formats other than MP3 & MP4, prefixed with the format's extension,
+    /// to their Telegram IDs.
+    #[serde(default)]
+    other: HashMap<Box<str>, Box<str>>,
 }
 
-pub struct Cache(RwLock<Inner>);
+impl Inner {
+    const fn files(&self, format: OutputFormat) -> &HashMap<Box<str>, Box<str>> {
+        match format {
+            OutputFormat::Mp4 => &self.videos,
+            OutputFormat::Mp3 => &self.tracks,
+            _ => &self.other,
+        }
+    }
+
+    const fn files_mut(&mut self, format: OutputFormat) -> &mut HashMap<Box<str>, Box<str>> {
+        match format {
+            OutputFormat::Mp4 => &mut self.videos,
+            OutputFormat::Mp3 => &mut self.tracks,
+            _ => &mut self.other,
+        }
+    }
+}
+
+fn key(uri: &str, format: OutputFormat) -> Cow<'_, str> {
+    match format {
+        OutputFormat::Mp4 | OutputFormat::Mp3 => uri.into(),
+        _ => format!("{} {uri}", format.extension()).into(),
+    }
+}
+
+pub struct Cache {
+    inner: RwLock<Inner>,
+    path: String,
+}
 
 impl Cache {
-    pub fn new() -> Result<Self> {
-        let inner = match File::open(CACHE_PATH) {
+    /// `cache_dir` is expected to have a trailing slash.
+    pub fn new(cache_dir: &str) -> Result<Self> {
+        let path = format!("{cache_dir}tg_id_cache.json");
+        let inner = match File::open(&path) {
             Ok(file) => serde_json::from_reader(file)?,
             Err(e) if e.kind() == NotFound => default(),
             Err(e) => Err(e)?,
         };
-        Ok(Self(RwLock::new(inner)))
+        Ok(Self { inner: RwLock::new(inner), path })
     }
 
-    pub async fn get(&self, uri: &str, mkind: MediaKind) -> Option<RwLockReadGuard<str>> {
-        RwLockReadGuard::try_map(self.0.read().await, |inner| match mkind {
-            MediaKind::Video => inner.videos.get(uri),
-            MediaKind::Audio => inner.tracks.get(uri),
-        }.map(Deref::deref)).ok()
+    pub async fn get(&self, uri: &str, format: OutputFormat) -> Option<RwLockReadGuard<str>> {
+        let key = key(uri, format);
+        RwLockReadGuard::try_map(self.inner.read().await, |inner| {
+            inner.files(format).get(&*key).map(Deref::deref)
+        }).ok()
     }
 
-    pub async fn set(&self, uri: Box<str>, mkind: MediaKind, tg_id: Box<str>) {
-        let mut inner = self.0.write().await;
-        match mkind {
-            MediaKind::Video => inner.videos.insert(uri, tg_id),
-            MediaKind::Audio => inner.tracks.insert(uri, tg_id),
-        };
+    pub async fn set(&self, uri: &str, format: OutputFormat, tg_id: Box<str>) {
+        let key = key(uri, format).into();
+        self.inner.write().await.files_mut(format).insert(key, tg_id);
     }
 
     pub async fn sync(&self) -> Result {
-        serde_json::to_writer(File::create(CACHE_PATH)?, &*self.0.read().await)?;
+        serde_json::to_writer(File::create(&self.path)?, &*self.inner.read().await)?;
         Ok(())
     }
 }
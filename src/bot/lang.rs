@@ -0,0 +1,294 @@
+//! Texts of the bot's messages in the supported languages, chosen by the user's language.
+
+use {
+    super::telegram::{BotCommand, User},
+    crate::download::{Error, FormatInfo, Info, MediaKind, Timestamp},
+    std::{fmt::Write, time::Duration},
+};
+
+/// The message catalogue of a language.
+pub struct Lang {
+    /// As in [`User::language_code`].
+    pub code: &'static str,
+    /// The name of the language in itself.
+    pub name: &'static str,
+    pub commands: &'static [BotCommand],
+    pub help_header: &'static str,
+    pub video: &'static str,
+    pub audio: &'static str,
+    pub best: &'static str,
+    pub source: &'static str,
+    pub choose_format: &'static str,
+    pub choose_quality: &'static str,
+    pub downloading_video: &'static str,
+    pub downloading_audio: &'static str,
+    /// Takes the 1-based index of the entry, the number of entries & the playlist's title.
+    pub downloading_entry: fn(usize, usize, &str) -> String,
+    /// Takes the number of the downloaded entries.
+    pub playlist_truncated: fn(usize) -> String,
+    pub playlist_too_large: &'static str,
+    /// Takes the number of the failed entries.
+    pub entries_failed: fn(usize) -> String,
+    pub no_link_video: &'static str,
+    pub no_link_audio: &'static str,
+    pub no_link_info: &'static str,
+    pub inline_playlist: &'static str,
+    pub video_too_large: &'static str,
+    pub track_too_large: &'static str,
+    pub is_stream: &'static str,
+    pub not_found: &'static str,
+    pub busy: &'static str,
+    pub download_failed: &'static str,
+    pub info_failed: &'static str,
+    pub uploader: &'static str,
+    pub duration: &'static str,
+    pub live_stream: &'static str,
+    /// Takes the number of entries.
+    pub playlist_len: fn(usize) -> String,
+    pub available_formats: &'static str,
+    pub megabytes: &'static str,
+    /// Counterpart of [`Error::reason`].
+    pub reason: fn(&Error) -> Option<&'static str>,
+    pub settings: &'static str,
+    pub caption: &'static str,
+    pub on: &'static str,
+    pub off: &'static str,
+    pub language: &'static str,
+    /// The language of the user's Telegram client.
+    pub auto_language: &'static str,
+    /// Shown in place of the options of a media kind that aren't set.
+    pub default_options: &'static str,
+}
+
+pub static EN: Lang = Lang {
+    code: "en",
+    name: "English",
+    commands: &[
+        BotCommand { command: "/help", description: "Show this message" },
+        BotCommand { command: "/video", description: "Download a video via the provided link" },
+        BotCommand { command: "/audio", description: "Download audio via the provided link" },
+        BotCommand { command: "/info", description: "Show what can be downloaded via the link" },
+        BotCommand { command: "/settings", description: "Change the default format & more" },
+    ],
+    help_header: "Available commands:",
+    video: "Video",
+    audio: "Audio",
+    best: "Best",
+    source: "Source",
+    choose_format: "Choose the format:",
+    choose_quality: "Choose the quality:",
+    downloading_video: "Downloading video...",
+    downloading_audio: "Downloading audio...",
+    downloading_entry: |index, len, title| format!("Downloading {index}/{len} of \"{title}\"..."),
+    playlist_truncated: |len| format!("Only the first {len} entries were downloaded"),
+    playlist_too_large: "The playlist is too large, the rest of it was skipped",
+    entries_failed: |n| format!("{n} entries couldn't be downloaded"),
+    no_link_video: "No link provided\n\
+        An example of using the command:\n\
+        \t/video https://www.youtube.com/watch?v=dQw4w9WgXcQ\n\
+        The format, quality & section can also be chosen:\n\
+        \t/video webm 720p 0:30-1:00 https://www.youtube.com/watch?v=dQw4w9WgXcQ\n\
+        Supported formats: mp4, webm, mkv",
+    no_link_audio: "No link provided\n\
+        An example of using the command:\n\
+        \t/audio https://www.youtube.com/watch?v=dQw4w9WgXcQ\n\
+        The format, quality & section can also be chosen:\n\
+        \t/audio opus 128k 0:30-1:00 https://www.youtube.com/watch?v=dQw4w9WgXcQ\n\
+        Supported formats: mp3, m4a, opus, flac, wav",
+    no_link_info: "No link provided\n\
+        An example of using the command:\n\
+        \t/info https://www.youtube.com/watch?v=dQw4w9WgXcQ",
+    inline_playlist: "Playlists can't be sent via inline mode, send the link to the bot instead",
+    video_too_large: "The video is too large",
+    track_too_large: "The track is too large",
+    is_stream: "Live streams can't be downloaded while they're ongoing",
+    not_found: "The provided link doesn't point to an existing video/track.\n\
+        Make sure the link is copied correctly and try again.",
+    busy: "The bot is busy right now, try again in a few minutes",
+    download_failed: "An unexpected error occured while downloading",
+    info_failed: "An unexpected error occured while getting the info",
+    uploader: "Uploader",
+    duration: "Duration",
+    live_stream: "Live stream",
+    playlist_len: |len| format!("Playlist of {len} entries"),
+    available_formats: "Available formats:",
+    megabytes: "MB",
+    reason: Error::reason,
+    settings: "Settings, applied when a command or a link doesn't specify otherwise:",
+    caption: "Caption",
+    on: "on",
+    off: "off",
+    language: "Language",
+    auto_language: "Automatic",
+    default_options: "default",
+};
+
+pub static RU: Lang = Lang {
+    code: "ru",
+    name: "Русский",
+    commands: &[
+        BotCommand { command: "/help", description: "Показать это сообщение" },
+        BotCommand { command: "/video", description: "Скачать видео по ссылке" },
+        BotCommand { command: "/audio", description: "Скачать аудио по ссылке" },
+        BotCommand { command: "/info", description: "Показать, что можно скачать по ссылке" },
+        BotCommand { command: "/settings", description: "Изменить формат по умолчанию и др." },
+    ],
+    help_header: "Доступные команды:",
+    video: "Видео",
+    audio: "Аудио",
+    best: "Лучшее",
+    source: "Источник",
+    choose_format: "Выберите формат:",
+    choose_quality: "Выберите качество:",
+    downloading_video: "Скачиваю видео...",
+    downloading_audio: "Скачиваю аудио...",
+    downloading_entry: |index, len, title| format!("Скачиваю {index}/{len} из «{title}»..."),
+    // Phrased as "label: number" to avoid the plural forms
+    playlist_truncated: |len| format!("Скачано только первых записей плейлиста: {len}"),
+    playlist_too_large: "Плейлист слишком большой, остаток пропущен",
+    entries_failed: |n| format!("Не удалось скачать записей: {n}"),
+    no_link_video: "Ссылка не указана\n\
+        Пример использования команды:\n\
+        \t/video https://www.youtube.com/watch?v=dQw4w9WgXcQ\n\
+        Также можно выбрать формат, качество и фрагмент:\n\
+        \t/video webm 720p 0:30-1:00 https://www.youtube.com/watch?v=dQw4w9WgXcQ\n\
+        Доступные форматы: mp4, webm, mkv",
+    no_link_audio: "Ссылка не указана\n\
+        Пример использования команды:\n\
+        \t/audio https://www.youtube.com/watch?v=dQw4w9WgXcQ\n\
+        Также можно выбрать формат, качество и фрагмент:\n\
+        \t/audio opus 128k 0:30-1:00 https://www.youtube.com/watch?v=dQw4w9WgXcQ\n\
+        Доступные форматы: mp3, m4a, opus, flac, wav",
+    no_link_info: "Ссылка не указана\n\
+        Пример использования команды:\n\
+        \t/info https://www.youtube.com/watch?v=dQw4w9WgXcQ",
+    inline_playlist: "Плейлисты нельзя отправить через инлайн-режим, отправьте ссылку боту",
+    video_too_large: "Видео слишком большое",
+    track_too_large: "Трек слишком большой",
+    is_stream: "Прямые трансляции нельзя скачать, пока они идут",
+    not_found: "Ссылка не ведёт на существующее видео или трек.\n\
+        Проверьте, что ссылка скопирована правильно, и попробуйте снова.",
+    busy: "Бот сейчас занят, попробуйте через несколько минут",
+    download_failed: "При скачивании произошла непредвиденная ошибка",
+    info_failed: "При получении информации произошла непредвиденная ошибка",
+    uploader: "Автор",
+    duration: "Длительность",
+    live_stream: "Прямая трансляция",
+    playlist_len: |len| format!("Плейлист, записей: {len}"),
+    available_formats: "Доступные форматы:",
+    megabytes: "МБ",
+    reason: |err| Some(match err {
+        Error::Private(_) => "Медиа скрыто настройками приватности",
+        Error::AgeRestricted(_) => "У медиа есть возрастные ограничения, его нельзя скачать",
+        Error::GeoBlocked(_) => "Медиа недоступно в стране сервера",
+        Error::Removed(_) => "Медиа было удалено",
+        Error::MembersOnly(_) => "Медиа доступно только спонсорам канала",
+        Error::LoginRequired(_) => "Медиа доступно только авторизованным пользователям",
+        Error::RateLimited(_) => "Платформа ограничивает запросы, попробуйте позже",
+        Error::UnsupportedUrl(_) => "Ссылки такого вида не поддерживаются",
+        _ => return None,
+    }),
+    settings: "Настройки, применяемые, если в команде или ссылке не указано иное:",
+    caption: "Подпись",
+    on: "вкл.",
+    off: "выкл.",
+    language: "Язык",
+    auto_language: "Автоматически",
+    default_options: "по умолчанию",
+};
+
+/// All the supported languages, the default one first.
+pub static ALL: [&Lang; 2] = [&EN, &RU];
+
+impl Lang {
+    /// Finds the catalogue by a language code, e.g. "ru" or "ru-RU".
+    pub fn get(code: &str) -> Option<&'static Self> {
+        // Only the primary subtag matters, e.g. "ru" in "ru-RU"
+        let code = code.split('-').next().unwrap_or_default();
+        ALL.into_iter().find(|lang| lang.code == code)
+    }
+
+    /// Chooses the `preferred` language if it's supported, otherwise the language of `user`,
+    /// falling back to the default language.
+    pub fn of(preferred: Option<&str>, user: Option<&User>) -> &'static Self {
+        preferred.and_then(Self::get)
+            .or_else(|| Self::get(user?.language_code.as_deref()?))
+            .unwrap_or(&EN)
+    }
+
+    pub fn help(&self) -> String {
+        let mut text = format!("{}\n\n", self.help_header);
+        for &BotCommand { command, description } in self.commands {
+            _ = writeln!(text, "{command} - {description}");
+        }
+        text
+    }
+
+    pub const fn downloading(&self, mkind: MediaKind) -> &'static str {
+        match mkind {
+            MediaKind::Video => self.downloading_video,
+            MediaKind::Audio => self.downloading_audio,
+        }
+    }
+
+    pub const fn kind(&self, mkind: MediaKind) -> &'static str {
+        match mkind {
+            MediaKind::Video => self.video,
+            MediaKind::Audio => self.audio,
+        }
+    }
+
+    pub const fn no_link(&self, mkind: MediaKind) -> &'static str {
+        match mkind {
+            MediaKind::Video => self.no_link_video,
+            MediaKind::Audio => self.no_link_audio,
+        }
+    }
+
+    /// Explanation for the user of why the media couldn't be downloaded.
+    pub fn error(&self, err: &Error, mkind: MediaKind) -> &'static str {
+        match err {
+            Error::TooLarge => match mkind {
+                MediaKind::Video => self.video_too_large,
+                MediaKind::Audio => self.track_too_large,
+            },
+            Error::IsStream => self.is_stream,
+            Error::NotFound | Error::InvalidLink => self.not_found,
+            Error::Busy => self.busy,
+            err => (self.reason)(err).unwrap_or(self.download_failed),
+        }
+    }
+
+    /// Details about the media for the reply to /info.
+    pub fn info(&self, info: &Info) -> String {
+        let mut text = format!("{}\n", info.title);
+        if let Some(uploader) = &info.uploader {
+            _ = writeln!(text, "{}: {uploader}", self.uploader);
+        }
+        if let Some(duration) = info.duration {
+            _ = writeln!(text, "{}: {}", self.duration, Timestamp(Duration::from_secs(duration)));
+        }
+        if info.is_live {
+            _ = writeln!(text, "{}", self.live_stream);
+        }
+        if let Some(len) = info.playlist_len {
+            _ = writeln!(text, "{}", (self.playlist_len)(len));
+        }
+        if !info.formats.is_empty() {
+            _ = writeln!(text, "\n{}", self.available_formats);
+        }
+        for FormatInfo { kind, quality, filesize } in &info.formats {
+            _ = write!(text, "{} {quality}", self.kind(*kind));
+            if let Some(size) = filesize {
+                #[expect(clippy::cast_precision_loss, reason = "an approximation is enough")]
+                let mb = *size as f64 / 1_000_000.0;
+                _ = write!(text, " ~{mb:.1} {}", self.megabytes);
+            }
+            text.push('\n');
+        }
+        if let Some(thumbnail) = &info.thumbnail {
+            _ = write!(text, "\n{thumbnail}");
+        }
+        text
+    }
+}
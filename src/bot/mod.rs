@@ -1,35 +1,49 @@
 pub mod telegram;
 mod cache;
+mod lang;
+mod settings;
 
 use {
-    self::cache::Cache, crate::{download, stats::Stats, try_harder_async, utils::{default, Result}}, axum::{extract::State, Json}, futures::{FutureExt, Stream}, log::{logger, set_max_level}, reqwest::{multipart::Part, Body}, std::{io, mem::take, sync::{atomic::{AtomicBool, Ordering::Relaxed}, Arc}}, telegram::{
-        DeleteMessage, DeleteWebhook, EditMessageText, GetMe, MediaKind, Message,
-        MessageCommon, MessageEntity, MessageEntityKind, MessageKind, SendAudio, SendMessage,
-        SendVideo, SetMyCommands, SetWebhook, Update, UpdateKind,
-    }, tokio::try_join
+    self::{cache::Cache, lang::Lang, settings::{Settings, UserSettings}}, crate::{config::{Config, UpdateMode}, download::{self, Download, OutputFormat}, stats::Stats, try_harder_async, utils::{default, Result}}, axum::{extract::{rejection::JsonRejection, ConnectInfo, State}, http::{HeaderMap, StatusCode}, Json}, futures::{FutureExt, Stream}, log::{logger, set_max_level}, reqwest::{multipart::Part, Body}, std::{collections::{HashSet, VecDeque}, fmt::Debug, io, mem::take, net::SocketAddr, panic::AssertUnwindSafe, sync::{atomic::{AtomicBool, Ordering::Relaxed}, Arc, Mutex, MutexGuard, PoisonError}, time::{Duration, Instant}}, telegram::{
+        AnswerCallbackQuery, AnswerInlineQuery, CallbackQuery, ChatKind, ChosenInlineResult,
+        DeleteMessage, DeleteWebhook, EditMessageCaption, EditMessageMedia, EditMessageText,
+        GetMe, GetUpdates, InlineKeyboardButton, InlineKeyboardButtonKind, InlineKeyboardMarkup,
+        InlineQuery, InlineQueryResult, InputMedia,
+        MediaKind, Message, MessageCommon, MessageEntity, MessageEntityKind, MessageKind,
+        SendAudio, SendDocument, SendMessage, SendVideo, SetMyCommands, SetWebhook, Update,
+        UpdateKind, User,
+    }, tokio::{sync::OnceCell, task::JoinHandle, time::sleep, try_join}, tokio_util::task::TaskTracker
 };
 
-mod en {
-    use {std::{sync::LazyLock, fmt::Write}, super::telegram::BotCommand};
-
-    pub static COMMANDS: [BotCommand; 3] = [
-        BotCommand { command: "/help", description: "Show this message" },
-        BotCommand { command: "/video", description: "Download a video via the provided link" },
-        BotCommand { command: "/audio", description: "Download audio via the provided link" },
-    ];
-
-    pub static HELP_MSG: LazyLock<String> = LazyLock::new(|| {
-        let mut text = "Available commands:\n\n".to_owned();
-        for &BotCommand { command, description } in &COMMANDS {
-            _ = writeln!(text, "{command} - {description}");
-        }
-        text
-    });
-
-    pub static LANG_CODE: &str = "en";
-}
+/// Header carrying the secret token set via `setWebhook`.
+const SECRET_TOKEN_HEADER: &str = "X-Telegram-Bot-Api-Secret-Token";
+/// The number of the latest update IDs remembered to drop the redelivered updates.
+const MAX_RECENT_UPDATES: usize = 1024;
+/// The kinds of updates handled by the bot, the others aren't received at all.
+const ALLOWED_UPDATES: &[&str] =
+    &["message", "inline_query", "chosen_inline_result", "callback_query"];
+/// How long a `getUpdates` request waits for new updates, in seconds.
+const POLL_TIMEOUT: u32 = 50;
+/// How long to wait before retrying a failed `getUpdates` request.
+const POLL_RETRY_DELAY: Duration = Duration::from_secs(5);
+/// Updates with an invalid secret token are reported at most once per this period, so that no one
+/// can flood the owner with the logs.
+const FORGED_UPDATES_REPORT_PERIOD: Duration = Duration::from_mins(10);
+/// How long Telegram may cache the answers to inline queries, in seconds.
+const INLINE_CACHE_TIME: u32 = 60;
+/// How many links in a single message are offered to be downloaded.
+const MAX_DETECTED_LINKS: usize = 3;
+/// The number of buttons in a row of a keyboard offering the formats or the qualities.
+const KEYBOARD_ROW_LEN: usize = 4;
+/// Offered in the settings, since the qualities actually available depend on the media.
+const VIDEO_QUALITIES: [&str; 7] = ["best", "2160p", "1440p", "1080p", "720p", "480p", "360p"];
+const AUDIO_QUALITIES: [&str; 6] = ["best", "320k", "256k", "192k", "128k", "64k"];
+/// Contents of the document sent via inline mode in place of the media until it's downloaded.
+const PLACEHOLDER: &[u8] = b"The media is being downloaded, it'll replace this file shortly.\n";
 
 pub struct Bot {
+    /// The bot's own user ID.
+    id: u64,
     username: Box<str>,
     /// Inserted into all videos & tracks sent by the bot.
     caption: Box<str>,
@@ -38,42 +52,216 @@ pub struct Bot {
     pub is_active: AtomicBool,
     stats: Stats,
     cache: Cache,
+    /// Per-user preferences, stored next to `cache`.
+    settings: Settings,
+    downloader: Arc<download::Downloader>,
+    updates: UpdateMode,
+    /// Expected in [`SECRET_TOKEN_HEADER`] of the updates received via the webhook.
+    webhook_secret: Box<str>,
+    /// The task receiving the updates in [`UpdateMode::Polling`].
+    poller: Mutex<Option<JoinHandle<()>>>,
+    /// The updates being processed, waited for by [`deinit`].
+    tasks: TaskTracker,
+    recent_updates: Mutex<RecentUpdates>,
+    forged_updates: Mutex<ForgedUpdates>,
+    /// Telegram file ID of [`PLACEHOLDER`], uploaded on first use.
+    placeholder: OnceCell<Box<str>>,
+}
+
+/// The user an update came from, as far as the bot is concerned.
+struct Sender {
+    /// `None` if the sender is anonymous, e.g. a channel.
+    id: Option<u64>,
+    lang: &'static Lang,
+    settings: UserSettings,
+}
+
+/// IDs of the latest updates, oldest first.
+#[derive(Default)]
+struct RecentUpdates {
+    ids: HashSet<u64>,
+    order: VecDeque<u64>,
+}
+
+/// Updates received via the webhook with an invalid secret token.
+#[derive(Default)]
+struct ForgedUpdates {
+    /// The number of the updates received since the last report.
+    unreported: usize,
+    last_report: Option<Instant>,
+}
+
+impl RecentUpdates {
+    /// Returns `false` if `id` was inserted already.
+    fn insert(&mut self, id: u64) -> bool {
+        if !self.ids.insert(id) {
+            return false;
+        }
+        self.order.push_back(id);
+        if self.order.len() > MAX_RECENT_UPDATES {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        true
+    }
 }
 
 impl Bot {
-    async fn new(stats: Stats) -> Result<Self> {
-        let client = telegram::Client::default();
-        let username = client.request(&GetMe).await?.username
-            .ok_or_else(|| io::Error::other("no bot username"))?;
+    async fn new(config: &Config, stats: Stats, downloader: Arc<download::Downloader>)
+        -> Result<Self>
+    {
+        let client = telegram::Client::new(&config.telegram.api_url, &config.telegram.token);
+        let me = client.request(&GetMe).await?;
+        let username = me.username.ok_or_else(|| io::Error::other("no bot username"))?;
         let res = Self {
+            id: me.id,
             caption: format!("@{username}").into(),
-            owner_id: env!("OWNER_TELEGRAM_ID").parse()?,
-            cache: Cache::new()?,
+            owner_id: config.telegram.owner_id,
+            cache: Cache::new(&config.cache_dir)?,
+            settings: Settings::new(&config.cache_dir)?,
             is_active: AtomicBool::new(false),
             stats,
             client,
             username,
+            downloader,
+            updates: config.telegram.updates,
+            webhook_secret: config.telegram.webhook_secret.as_deref().unwrap_or_default().into(),
+            poller: Mutex::new(None),
+            tasks: TaskTracker::new(),
+            recent_updates: default(),
+            forged_updates: default(),
+            placeholder: OnceCell::new(),
         };
 
-        res.client.request(&SetWebhook {
-            url: concat!(env!("URL"), "/bot"),
-            drop_pending_updates: true,
-            secret_token: None, // TODO: add this
-        }).await?;
-        res.client.request(&SetMyCommands { commands: &en::COMMANDS, language_code: None }).await?;
+        match res.updates {
+            UpdateMode::Webhook => res.client.request(&SetWebhook {
+                url: &format!("{}/bot", config.url),
+                drop_pending_updates: true,
+                secret_token: Some(&res.webhook_secret),
+                allowed_updates: ALLOWED_UPDATES,
+            }).await?,
+            // Updates can't be requested while a webhook is set.
+            UpdateMode::Polling => res.client.request(&DeleteWebhook {
+                drop_pending_updates: true,
+            }).await?,
+        };
+        res.client.request(&SetMyCommands { commands: lang::EN.commands, language_code: None })
+            .await?;
+        for lang in lang::ALL {
+            res.client.request(&SetMyCommands {
+                commands: lang.commands,
+                language_code: Some(lang.code),
+            }).await?;
+        }
         res.client.request(&SendMessage { chat_id: res.owner_id, text: "ON", ..default() }).await?;
         res.is_active.store(true, Relaxed);
 
         Ok(res)
     }
 
+    /// Receives the updates via `getUpdates` until aborted.
+    #[expect(clippy::infinite_loop, reason = "aborted by `deinit`")]
+    async fn poll(self: Arc<Self>) {
+        let mut offset = None;
+        loop {
+            let updates = match self.client.request(&GetUpdates {
+                offset,
+                timeout: POLL_TIMEOUT,
+                allowed_updates: ALLOWED_UPDATES,
+            }).await {
+                Ok(updates) => updates,
+                Err(err) => {
+                    log::error!("Failed to get updates from Telegram: {err}");
+                    sleep(POLL_RETRY_DELAY).await;
+                    continue;
+                }
+            };
+
+            for update in updates {
+                let id = update["update_id"].as_u64();
+                offset = id.map(|id| id + 1).max(offset);
+                match serde_json::from_value(update) {
+                    Ok(update) => self.spawn(update),
+                    Err(err) => log::warn!("Skipping update {id:?}: {err}"),
+                }
+            }
+        }
+    }
+
+    fn recent_updates(&self) -> MutexGuard<'_, RecentUpdates> {
+        self.recent_updates.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Logs an update with an invalid secret token, see [`FORGED_UPDATES_REPORT_PERIOD`].
+    fn report_forged_update(&self, addr: SocketAddr) {
+        let unreported = {
+            let mut forged = self.forged_updates.lock().unwrap_or_else(PoisonError::into_inner);
+            forged.unreported += 1;
+            if forged.last_report.is_some_and(|t| t.elapsed() < FORGED_UPDATES_REPORT_PERIOD) {
+                return;
+            }
+            forged.last_report = Some(Instant::now());
+            take(&mut forged.unreported)
+        };
+        log::warn!(
+            "Rejected {unreported} update(s) with an invalid secret token since the last report, \
+            the latest from {addr}"
+        );
+    }
+
+    /// Handles `update` in the background, unless it was received already; errors & panics are
+    /// logged.
+    fn spawn(self: &Arc<Self>, update: Update) {
+        if !self.recent_updates().insert(update.id) {
+            log::info!("Dropping update {}, which was received already", update.id);
+            return;
+        }
+        let bot = self.clone();
+        self.tasks.spawn(async move {
+            match AssertUnwindSafe(bot.handle_update(&update)).catch_unwind().await {
+                Ok(Ok(())) => (),
+                Ok(Err(err)) => {
+                    log::error!("Telegram bot error\nUpdate: {update:#?}\nError: {err}");
+                }
+                Err(_) => log::error!("Telegram bot panicked\nUpdate: {update:#?}"),
+            }
+        });
+    }
+
     async fn handle_update(&self, update: &Update) -> Result {
         log::info!("Bot received update: {update:#?}");
         if let Some(id) = update.from().map(|u| u.id) {
             self.stats.record_bot_user(id);
         }
 
-        let UpdateKind::Message(Message { chat, kind, id, .. }) = &update.kind;
+        match &update.kind {
+            UpdateKind::Message(msg) => self.handle_message(msg).await,
+            UpdateKind::InlineQuery(query) => self.handle_inline_query(query).await,
+            UpdateKind::ChosenInlineResult(result) => {
+                self.handle_chosen_inline_result(result).await
+            }
+            UpdateKind::CallbackQuery(query) => self.handle_callback_query(query).await,
+        }
+    }
+
+    async fn sender(&self, user: Option<&User>) -> Sender {
+        let settings = match user {
+            Some(user) => self.settings.get(user.id).await,
+            None => default(),
+        };
+        let lang = Lang::of(settings.lang.as_deref(), user);
+        Sender { id: user.map(|user| user.id), lang, settings }
+    }
+
+    /// The caption of the media sent to a user with `settings`.
+    fn caption(&self, settings: &UserSettings) -> &str {
+        if settings.caption { &self.caption } else { "" }
+    }
+
+    async fn handle_message(&self, msg: &Message) -> Result {
+        let Message { chat, kind, id, .. } = msg;
+        let sender = self.sender(msg.from.as_ref()).await;
         let MessageKind::Common(MessageCommon { media_kind, .. }) = kind;
         let MediaKind::Text { text, entities } = media_kind else {
             return Ok(());
@@ -84,6 +272,9 @@ impl Bot {
             kind: MessageEntityKind::BotCommand,
         }, ..] = &**entities
         else {
+            if self.is_addressed(msg, text, entities) {
+                self.handle_links(sender.lang, *id, chat.id, text, entities).await?;
+            }
             return Ok(());
         };
 
@@ -91,22 +282,366 @@ impl Bot {
         if let Some(cmd) = cmd.split_once('@')
             .map_or(Some(cmd), |(cmd, dst)| (dst == &*self.username).then_some(cmd))
         {
-            self.handle_command(*id, chat.id, cmd, args).await?;
+            self.handle_command(&sender, *id, chat.id, cmd, args).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether a message without a command is meant for the bot: in groups, only the messages
+    /// mentioning the bot or replying to it are, like with the group privacy mode enabled, so that
+    /// the bot doesn't answer every link posted in a group it can read in full.
+    fn is_addressed(&self, msg: &Message, text: &str, entities: &[MessageEntity]) -> bool {
+        if matches!(msg.chat.kind, ChatKind::Private { .. }) {
+            return true;
+        }
+        let is_reply = msg.reply_to.as_ref()
+            .and_then(|reply| reply.from.as_ref())
+            .is_some_and(|user| user.id == self.id);
+        is_reply || entities.iter().any(|entity| {
+            matches!(entity.kind, MessageEntityKind::Mention)
+                && entity.text(text)
+                    .and_then(|x| x.strip_prefix('@'))
+                    .is_some_and(|x| x.eq_ignore_ascii_case(&self.username))
+        })
+    }
+
+    /// Replies to each supported link in the message with the buttons to download it; the text
+    /// of the reply is the link itself, so that [`Self::handle_callback_query`] can find it. The
+    /// link is kept as is rather than canonicalised, since it may also imply the options, e.g.
+    /// the start of the clip via `t=`.
+    async fn handle_links(
+        &self,
+        lang: &Lang,
+        msg_id: i32,
+        chat_id: i64,
+        text: &str,
+        entities: &[MessageEntity],
+    ) -> Result {
+        let links = entities.iter().filter_map(|entity| match &entity.kind {
+            MessageEntityKind::Url => entity.text(text),
+            MessageEntityKind::TextLink { url } => Some(&**url),
+            _ => None,
+        });
+        let mut offered = vec![];
+        for link in links {
+            if offered.len() == MAX_DETECTED_LINKS {
+                break;
+            }
+            let Some(input) = self.downloader.input(link).await else { continue };
+            let uri = input.to_string();
+            if offered.contains(&uri) {
+                continue;
+            }
+            let buttons = [
+                (download::MediaKind::Video, lang.video, "video"),
+                (download::MediaKind::Audio, lang.audio, "audio"),
+            ];
+            let buttons = buttons.into_iter()
+                .filter(|&(mkind, ..)| input.allows(mkind))
+                .map(|(_, text, data)| (text.to_owned(), data.to_owned()));
+            self.client.request(&SendMessage {
+                chat_id,
+                text: link,
+                disable_web_page_preview: true,
+                reply_to_message_id: Some(msg_id),
+                reply_markup: Some(keyboard(buttons)),
+            }).await?;
+            offered.push(uri);
         }
+        Ok(())
+    }
 
+    /// Handles the buttons attached to the links offered by [`Self::handle_links`]; the link is
+    /// the first line of the message with the buttons.
+    /// The data of a button is the kind of the media followed by the options chosen so far, e.g.
+    /// "video webm 720p": the format is chosen first, then the quality, after which the media is
+    /// downloaded in place of the message with the buttons. If the user has chosen the format &
+    /// the quality in advance via /settings, the media is downloaded right away.
+    async fn handle_callback_query(&self, query: &CallbackQuery) -> Result {
+        // Stops the button's loading animation
+        self.client.request(&AnswerCallbackQuery { callback_query_id: &query.id, text: None })
+            .await?;
+        let Some(data) = query.data.as_deref() else { return Ok(()) };
+        let sender = self.sender(Some(&query.from)).await;
+        let lang = sender.lang;
+        let mut args = data.split_whitespace();
+        let mkind = match args.next() {
+            Some("video") => download::MediaKind::Video,
+            Some("audio") => download::MediaKind::Audio,
+            Some("settings") => return self.handle_settings_callback(query, sender, args).await,
+            _ => return Ok(()),
+        };
+        let mut options = download::Options::new(mkind);
+        let n_chosen = args.filter(|arg| options.parse_arg(arg)).count();
+        let Some(Message {
+            id: message_id,
+            chat,
+            reply_to,
+            kind: MessageKind::Common(common),
+            ..
+        }) = &query.message
+        else {
+            return Ok(());
+        };
+        let MediaKind::Text { text, .. } = &common.media_kind else {
+            return Ok(());
+        };
+        let (chat_id, message_id) = (chat.id, *message_id);
+        let link = text.lines().next().unwrap_or_default();
+
+        match n_chosen {
+            0 if sender.settings.args(mkind).is_empty() => {
+                let formats = download::OutputFormat::ALL.into_iter()
+                    .filter(|format| format.kind() == mkind)
+                    .map(download::OutputFormat::extension)
+                    .map(|ext| (ext.to_owned(), format!("{data} {ext}")));
+                self.client.request(&EditMessageText {
+                    chat_id,
+                    message_id,
+                    text: &format!("{link}\n\n{}", lang.choose_format),
+                    disable_web_page_preview: true,
+                    reply_markup: Some(keyboard(formats)),
+                }).await?;
+            }
+
+            1 => {
+                let info = match self.downloader.info(link).await {
+                    Ok(info) => info,
+                    Err(err) => {
+                        let text = &format!("{link}\n\n{}", lang.error(&err, mkind));
+                        self.client.request(&EditMessageText {
+                            chat_id,
+                            message_id,
+                            text,
+                            disable_web_page_preview: true,
+                            ..default()
+                        }).await?;
+                        return Ok(());
+                    }
+                };
+                let mut qualities = vec!["best"];
+                for format in info.formats.iter().filter(|format| format.kind == mkind) {
+                    if !qualities.contains(&&*format.quality) {
+                        qualities.push(&format.quality);
+                    }
+                }
+                let qualities = qualities.into_iter().map(|quality| {
+                    let text = if quality == "best" { lang.best } else { quality };
+                    (text.to_owned(), format!("{data} {quality}"))
+                });
+                self.client.request(&EditMessageText {
+                    chat_id,
+                    message_id,
+                    text: &format!("{link}\n\n{}\n{}", info.title, lang.choose_quality),
+                    disable_web_page_preview: true,
+                    reply_markup: Some(keyboard(qualities)),
+                }).await?;
+            }
+
+            _ => {
+                let options = match n_chosen {
+                    0 => sender.settings.options(mkind),
+                    _ => options,
+                };
+                self.client.request(&EditMessageText {
+                    chat_id,
+                    message_id,
+                    text: lang.downloading(mkind),
+                    ..default()
+                }).await?;
+                let reply_to = reply_to.as_ref().map(|msg| msg.id);
+                self.download(&sender, reply_to, chat_id, message_id, link, options).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Offers the media behind the link in the query: the files sent before are offered as is,
+    /// the rest as a placeholder that's replaced with the media once it's chosen & downloaded.
+    async fn handle_inline_query(&self, query: &InlineQuery) -> Result {
+        let sender = self.sender(Some(&query.from)).await;
+        let lang = sender.lang;
+        let mut results = vec![];
+        if let Some(input) = self.downloader.input(query.query.trim()).await {
+            let uri: Box<str> = input.to_string().into();
+            for (mkind, id, title) in [
+                (download::MediaKind::Video, "video", lang.video),
+                (download::MediaKind::Audio, "audio", lang.audio),
+            ] {
+                if !input.allows(mkind) {
+                    continue;
+                }
+                let options = input.apply_defaults(sender.settings.options(mkind));
+                let cached = if cacheable(options) {
+                    self.cache.get(&uri, options.format).await.map(|x| Box::from(&*x))
+                } else {
+                    None
+                };
+                let caption = self.caption(&sender.settings).into();
+                results.push(match (cached, options.format) {
+                    (Some(video_file_id), OutputFormat::Mp4) => InlineQueryResult::Video {
+                        id: format!("cached-{id}").into(),
+                        video_file_id,
+                        title: title.into(),
+                        caption,
+                    },
+                    (Some(audio_file_id), OutputFormat::Mp3 | OutputFormat::M4a) => {
+                        InlineQueryResult::Audio {
+                            id: format!("cached-{id}").into(),
+                            audio_file_id,
+                            caption,
+                        }
+                    }
+                    _ => InlineQueryResult::Document {
+                        id: id.into(),
+                        title: title.into(),
+                        document_file_id: self.placeholder().await?.into(),
+                        description: uri.clone(),
+                        caption: lang.downloading(mkind).into(),
+                        // Without a keyboard, Telegram doesn't report the ID of the sent message
+                        reply_markup: InlineKeyboardMarkup {
+                            inline_keyboard: vec![vec![InlineKeyboardButton {
+                                text: lang.source.into(),
+                                kind: InlineKeyboardButtonKind::Url(uri.clone()),
+                            }]],
+                        },
+                    },
+                });
+            }
+        }
+
+        self.client.request(&AnswerInlineQuery {
+            inline_query_id: &query.id,
+            results: &results,
+            cache_time: INLINE_CACHE_TIME,
+            // The results depend on the language & the settings of the user
+            is_personal: true,
+        }).await?;
         Ok(())
     }
 
-    async fn handle_command(&self, msg_id: i32, chat_id: i64, cmd: &str, args: &str) -> Result {
+    /// Replaces the placeholder sent via inline mode with the media.
+    /// Only received if inline feedback is enabled for the bot via `@BotFather`.
+    async fn handle_chosen_inline_result(&self, result: &ChosenInlineResult) -> Result {
+        let mkind = match &*result.result_id {
+            "video" => download::MediaKind::Video,
+            "audio" => download::MediaKind::Audio,
+            _ => return Ok(()),
+        };
+        let Some(inline_message_id) = result.inline_message_id.as_deref() else {
+            return Ok(());
+        };
+
+        let sender = self.sender(Some(&result.from)).await;
+        let res: Result<_> = try_harder_async! {
+            match self.inline_file_id(&sender, &result.query, mkind).await? {
+                Ok((format, file_id)) => {
+                    let (media, caption) = (&*file_id, self.caption(&sender.settings));
+                    self.client.request(&EditMessageMedia {
+                        inline_message_id,
+                        media: match format {
+                            OutputFormat::Mp3 | OutputFormat::M4a => {
+                                InputMedia::Audio { media, caption }
+                            }
+                            OutputFormat::Mp4 => InputMedia::Video { media, caption },
+                            _ => InputMedia::Document { media, caption },
+                        },
+                    }).await?;
+                    None
+                }
+                Err(text) => Some(text),
+            }
+        };
+        let caption = match &res {
+            Ok(None) => return Ok(()),
+            Ok(Some(text)) => text,
+            // Otherwise the placeholder would stay as is.
+            Err(_) => sender.lang.download_failed,
+        };
+        self.client.request(&EditMessageCaption { inline_message_id, caption }).await?;
+        res.map(drop)
+    }
+
+    /// Returns the file ID of [`PLACEHOLDER`], uploading it to the owner's chat on first use.
+    /// Messages sent via inline mode can only be turned from text into media on the recent
+    /// versions of the Bot API, so they're sent as a document from the start instead.
+    async fn placeholder(&self) -> Result<&str> {
+        Ok(self.placeholder.get_or_try_init(|| self.upload_placeholder()).await?)
+    }
+
+    async fn upload_placeholder(&self) -> Result<Box<str>> {
+        let chat_id = self.owner_id;
+        let payload = Part::bytes(PLACEHOLDER).file_name("placeholder.txt");
+        let msg = self.send(&SendDocument {
+            chat_id,
+            document: "attach://payload",
+            ..default()
+        }, Some(payload)).await?;
+        self.client.request(&DeleteMessage { chat_id, message_id: msg.id }).await?;
+        let MessageKind::Common(common) = msg.kind;
+        match common.media_kind {
+            MediaKind::Document { document } => Ok(document.id),
+            _ => Err(io::Error::other("unexpected media kind"))?,
+        }
+    }
+
+    /// Returns the format & the Telegram file ID of the media behind `link`. Messages sent via
+    /// inline mode can only be edited with files already uploaded to Telegram, so the media is
+    /// uploaded to the owner's chat first, unless it's cached.
+    /// The inner error is the explanation for the user.
+    #[expect(clippy::significant_drop_tightening, reason = "`media` is moved into the upload")]
+    async fn inline_file_id(&self, sender: &Sender, link: &str, mkind: download::MediaKind)
+        -> Result<Result<(OutputFormat, Box<str>), &'static str>>
+    {
+        let lang = sender.lang;
+        let Some(input) = self.downloader.input(link.trim()).await else {
+            return Ok(Err(lang.error(&download::Error::InvalidLink, mkind)));
+        };
+        let uri = input.to_string();
+        let options = input.apply_defaults(sender.settings.options(mkind));
+        // If the kind is disabled, the downloader reports it.
+        if cacheable(options) && input.allows(mkind) {
+            if let Some(file_id) = self.cache.get(&uri, options.format).await {
+                return Ok(Ok((options.format, (*file_id).into())));
+            }
+        }
+
+        let media = match self.downloader.get(input, options).await {
+            Ok(Download::Media(media)) => media,
+            Ok(Download::Playlist(_)) => return Ok(Err(lang.inline_playlist)),
+            Err(err) => return Ok(Err(lang.error(&err, mkind))),
+        };
+        let chat_id = self.owner_id;
+        let (message_id, file_id) =
+            self.upload_media(None, chat_id, options.format, "", media).await?;
+        self.client.request(&DeleteMessage { chat_id, message_id }).await?;
+        if cacheable(options) {
+            self.cache.set(&uri, options.format, file_id.clone()).await;
+        }
+        Ok(Ok((options.format, file_id)))
+    }
+
+    async fn handle_command(
+        &self,
+        sender: &Sender,
+        msg_id: i32,
+        chat_id: i64,
+        cmd: &str,
+        args: &str,
+    ) -> Result {
+        let lang = sender.lang;
         match cmd {
             "/resetstats" if chat_id == self.owner_id => self.handle_resetstats_command(chat_id).await,
             "/stats" if chat_id == self.owner_id => self.handle_stats_command(chat_id).await,
             "/logs" if chat_id == self.owner_id => self.handle_logs_command(),
             "/loglevel" if chat_id == self.owner_id => self.handle_loglevel_command(chat_id, args).await,
 
-            "/help" => self.handle_help_command(chat_id).await,
-            "/video" => self.handle_video_command(msg_id, chat_id, args).await,
-            "/audio" => self.handle_audio_command(msg_id, chat_id, args).await,
+            "/help" => self.handle_help_command(lang, chat_id).await,
+            "/video" => self.handle_video_command(sender, msg_id, chat_id, args).await,
+            "/audio" => self.handle_audio_command(sender, msg_id, chat_id, args).await,
+            "/info" => self.handle_info_command(lang, msg_id, chat_id, args).await,
+            "/settings" => self.handle_settings_command(sender, chat_id).await,
             _ => Ok(()),
         }
     }
@@ -151,42 +686,189 @@ impl Bot {
         Ok(())
     }
 
-    async fn handle_help_command(&self, chat_id: i64) -> Result {
-        self.client.request(&SendMessage { chat_id, text: &en::HELP_MSG, ..default() }).await?;
+    async fn handle_help_command(&self, lang: &Lang, chat_id: i64) -> Result {
+        self.client.request(&SendMessage { chat_id, text: &lang.help(), ..default() }).await?;
         Ok(())
     }
 
-    async fn handle_video_command(&self, msg_id: i32, chat_id: i64, args: &str) -> Result {
-        self.handle_media_command(msg_id, chat_id, args, download::MediaKind::Video).await
+    async fn handle_video_command(&self, sender: &Sender, msg_id: i32, chat_id: i64, args: &str)
+        -> Result
+    {
+        self.handle_media_command(sender, msg_id, chat_id, args, download::MediaKind::Video).await
     }
 
-    async fn handle_audio_command(&self, msg_id: i32, chat_id: i64, args: &str) -> Result {
-        self.handle_media_command(msg_id, chat_id, args, download::MediaKind::Audio).await
+    async fn handle_audio_command(&self, sender: &Sender, msg_id: i32, chat_id: i64, args: &str)
+        -> Result
+    {
+        self.handle_media_command(sender, msg_id, chat_id, args, download::MediaKind::Audio).await
+    }
+
+    async fn handle_info_command(&self, lang: &Lang, msg_id: i32, chat_id: i64, args: &str)
+        -> Result
+    {
+        let link = args.trim();
+        let text = if link.is_empty() {
+            lang.no_link_info.to_owned()
+        } else {
+            match self.downloader.info(link).await {
+                Ok(info) => lang.info(&info),
+                Err(err @ (download::Error::NotFound
+                    | download::Error::InvalidLink
+                    | download::Error::IsStream
+                    | download::Error::Busy)) => {
+                    lang.error(&err, download::MediaKind::Video).to_owned()
+                }
+                Err(err) => (lang.reason)(&err).unwrap_or(lang.info_failed).to_owned(),
+            }
+        };
+        self.client.request(&SendMessage {
+            chat_id,
+            text: &text,
+            reply_to_message_id: Some(msg_id),
+            ..default()
+        }).await?;
+        Ok(())
+    }
+
+    async fn handle_settings_command(&self, sender: &Sender, chat_id: i64) -> Result {
+        let Some(user_id) = sender.id else { return Ok(()) };
+        self.client.request(&SendMessage {
+            chat_id,
+            text: sender.lang.settings,
+            reply_markup: Some(settings_menu(sender.lang, user_id, &sender.settings)),
+            ..default()
+        }).await?;
+        Ok(())
+    }
+
+    /// Handles the buttons of the menu sent by [`Self::handle_settings_command`]; the data of a
+    /// button is "settings" followed by the ID of the user who sent /settings, the setting & the
+    /// values chosen so far, e.g. "settings 1234 video webm 720p". The presses of other users are
+    /// ignored, so that no one can change someone else's settings in a group.
+    async fn handle_settings_callback<'a>(
+        &self,
+        query: &CallbackQuery,
+        sender: Sender,
+        mut args: impl Iterator<Item = &'a str>,
+    ) -> Result {
+        let Some(Message { id: message_id, chat, .. }) = &query.message else { return Ok(()) };
+        let (chat_id, message_id) = (chat.id, *message_id);
+        let Sender { mut lang, .. } = sender;
+        let user_id = query.from.id;
+        if args.next().and_then(|id| id.parse().ok()) != Some(user_id) {
+            return Ok(());
+        }
+
+        let (text, reply_markup) = match (args.next(), args.next(), args.next()) {
+            (Some(kind @ ("video" | "audio")), format, quality) => {
+                let mkind = match kind {
+                    "video" => download::MediaKind::Video,
+                    _ => download::MediaKind::Audio,
+                };
+                let mut options = download::Options::new(mkind);
+                match (format, quality) {
+                    (Some("default"), _) => {
+                        let settings = self.settings.update(user_id, |settings| {
+                            *settings.args_mut(mkind) = default();
+                        }).await?;
+                        (lang.settings, settings_menu(lang, user_id, &settings))
+                    }
+                    (None, _) => {
+                        let formats = download::OutputFormat::ALL.into_iter()
+                            .filter(|format| format.kind() == mkind)
+                            .map(download::OutputFormat::extension)
+                            .map(|ext| (ext.to_owned(), format!("settings {user_id} {kind} {ext}")))
+                            .chain([(
+                                lang.default_options.to_owned(),
+                                format!("settings {user_id} {kind} default"),
+                            )]);
+                        (lang.choose_format, keyboard(formats))
+                    }
+                    (Some(format), None) if options.parse_arg(format) => {
+                        let qualities = match mkind {
+                            download::MediaKind::Video => &VIDEO_QUALITIES[..],
+                            download::MediaKind::Audio => &AUDIO_QUALITIES[..],
+                        };
+                        let qualities = qualities.iter().map(|&quality| {
+                            let text = if quality == "best" { lang.best } else { quality };
+                            (text.to_owned(), format!("settings {user_id} {kind} {format} {quality}"))
+                        });
+                        (lang.choose_quality, keyboard(qualities))
+                    }
+                    (Some(format), Some(quality))
+                        if options.parse_arg(format) && options.parse_arg(quality) =>
+                    {
+                        let args = format!("{} {}", options.format.extension(), options.quality);
+                        let settings = self.settings.update(user_id, |settings| {
+                            *settings.args_mut(mkind) = args.into();
+                        }).await?;
+                        (lang.settings, settings_menu(lang, user_id, &settings))
+                    }
+                    _ => return Ok(()),
+                }
+            }
+
+            (Some("caption"), ..) => {
+                let settings = self.settings.update(user_id, |settings| {
+                    settings.caption = !settings.caption;
+                }).await?;
+                (lang.settings, settings_menu(lang, user_id, &settings))
+            }
+
+            (Some("lang"), None, _) => {
+                let langs = lang::ALL.into_iter()
+                    .map(|l| (l.name.to_owned(), format!("settings {user_id} lang {}", l.code)))
+                    .chain([(
+                        lang.auto_language.to_owned(),
+                        format!("settings {user_id} lang auto"),
+                    )]);
+                (lang.language, keyboard(langs))
+            }
+
+            (Some("lang"), Some(code), _) => {
+                let settings = self.settings.update(user_id, |settings| {
+                    settings.lang = Lang::get(code).map(|lang| lang.code.into());
+                }).await?;
+                lang = Lang::of(settings.lang.as_deref(), Some(&query.from));
+                (lang.settings, settings_menu(lang, user_id, &settings))
+            }
+
+            _ => return Ok(()),
+        };
+        self.client.request(&EditMessageText {
+            chat_id,
+            message_id,
+            text,
+            reply_markup: Some(reply_markup),
+            ..default()
+        }).await?;
+        Ok(())
     }
 
-    #[expect(clippy::too_many_lines)]
     async fn handle_media_command(
         &self,
+        sender: &Sender,
         msg_id: i32,
         chat_id: i64,
         args: &str,
         mkind: download::MediaKind,
     ) -> Result {
-        let link = args.trim();
+        let lang = sender.lang;
+        // The explicit options override the user's defaults
+        let (mut link, mut options) = ("", sender.settings.options(mkind));
+        for arg in args.split_whitespace() {
+            if !options.parse_arg(arg) {
+                link = arg;
+            }
+        }
 
         if link.is_empty() {
             self.client.request(&SendMessage {
                 chat_id,
-                text: match mkind {
-                    download::MediaKind::Video => "No link provided\n\
-                        An example of using the command:\n\
-                        \t/video https://www.youtube.com/watch?v=dQw4w9WgXcQ",
-                    download::MediaKind::Audio => "No link provided\n\
-                        An example of using the command:\n\
-                        \t/audio https://www.youtube.com/watch?v=dQw4w9WgXcQ",
-                },
+                text: lang.no_link(mkind),
                 disable_web_page_preview: true,
                 reply_to_message_id: Some(msg_id),
+                ..default()
             }).await?;
             return Ok(());
         }
@@ -194,122 +876,253 @@ impl Bot {
         let Message { id: message_id, .. } = self.client.request(&SendMessage {
             chat_id,
             reply_to_message_id: Some(msg_id),
-            text: match mkind {
-                download::MediaKind::Video => "Downloading video...",
-                download::MediaKind::Audio => "Downloading audio...",
-            },
+            text: lang.downloading(mkind),
             ..default()
         }).await?;
+        self.download(sender, Some(msg_id), chat_id, message_id, link, options).await
+    }
 
+    /// Downloads the media & sends it in reply to `reply_to`, reporting the progress & the errors
+    /// in the message `message_id`, which is deleted once the media is sent.
+    async fn download(
+        &self,
+        sender: &Sender,
+        reply_to: Option<i32>,
+        chat_id: i64,
+        message_id: i32,
+        link: &str,
+        options: download::Options,
+    ) -> Result {
+        let (lang, caption) = (sender.lang, self.caption(&sender.settings));
+        let mkind = options.format.kind();
         #[expect(clippy::significant_drop_in_scrutinee)]
         match try_harder_async! {
-            let input = download::Input::from_uri(link).ok_or(Err(download::Error::InvalidLink))?;
+            let input = self.downloader.input(link).await
+                .ok_or(Err(download::Error::InvalidLink))?;
             let uri = input.to_string();
-            if let Some(cached_id) = self.cache.get(&uri, mkind).await {
-                Err(Ok(cached_id))?;
+            let options = input.apply_defaults(options);
+            // If the kind is disabled, the downloader reports it.
+            if cacheable(options) && input.allows(mkind) {
+                if let Some(cached_id) = self.cache.get(&uri, options.format).await {
+                    Err(Ok(cached_id))?;
+                }
             }
-            (uri, download::Media::get(input, mkind).await.map_err(Err)?)
+            (uri, options, self.downloader.get(input, options).await.map_err(Err)?)
         } {
-            Ok((uri, mut stream)) => {
-                let stream_size = stream.size_hint().0 as u64;
-                let filename = take(stream.filename_mut());
-                let payload = Part::stream_with_length(Body::wrap_stream(stream), stream_size)
-                    .file_name(filename)
-                    .mime_str(mkind.mime_type())?;
-
-                let msg = match mkind {
-                    download::MediaKind::Audio => self.client.multipart_request(&SendAudio {
-                        chat_id,
-                        audio: "attach://payload",
-                        caption: &self.caption,
-                        reply_to_message_id: Some(msg_id),
-                    }, payload).await?,
-                    download::MediaKind::Video => self.client.multipart_request(&SendVideo {
-                        chat_id,
-                        video: "attach://payload",
-                        caption: &self.caption,
-                        reply_to_message_id: Some(msg_id),
-                    }, payload).await?,
-                };
+            Ok((uri, options, Download::Media(media))) => {
+                let (_, tg_id) =
+                    self.upload_media(reply_to, chat_id, options.format, caption, media).await?;
                 self.client.request(&DeleteMessage { chat_id, message_id }).await?;
-
-                let MessageKind::Common(msg) = msg.kind;
-                let tg_id = match (msg.media_kind, mkind) {
-                    (MediaKind::Audio { audio }, download::MediaKind::Audio) => audio.id,
-                    (MediaKind::Video { video }, download::MediaKind::Video) => video.id,
-                    _ => Err(io::Error::other("unexpected media kind"))?,
-                };
-                self.cache.set(uri.into(), mkind, tg_id).await;
+                if cacheable(options) {
+                    self.cache.set(&uri, options.format, tg_id).await;
+                }
             }
 
-            Err(Ok(cached_id)) => _ = try_join! {
-                match mkind {
-                    download::MediaKind::Audio => self.client.request(&SendAudio {
+            Ok((_, options, Download::Playlist(mut playlist))) => {
+                let (len, mut failed) = (playlist.len(), 0);
+                for index in 1..=len {
+                    let text = &(lang.downloading_entry)(index, len, &playlist.title);
+                    self.client.request(&EditMessageText {
                         chat_id,
-                        audio: &cached_id,
-                        caption: &self.caption,
-                        reply_to_message_id: Some(msg_id),
-                    }).left_future(),
-                    download::MediaKind::Video => self.client.request(&SendVideo {
+                        message_id,
+                        text,
+                        ..default()
+                    }).await?;
+                    match playlist.next().await {
+                        None => break,
+                        Some(Ok(media)) => {
+                            self.upload_media(reply_to, chat_id, options.format, caption, media)
+                                .await?;
+                        }
+                        Some(Err(_)) => failed += 1,
+                    }
+                }
+
+                let mut notices = vec![];
+                if playlist.is_truncated() {
+                    notices.push((lang.playlist_truncated)(len));
+                }
+                if playlist.exceeds_max_size() {
+                    notices.push(lang.playlist_too_large.into());
+                }
+                if failed > 0 {
+                    notices.push((lang.entries_failed)(failed));
+                }
+                if notices.is_empty() {
+                    self.client.request(&DeleteMessage { chat_id, message_id }).await?;
+                } else {
+                    let text = &notices.join("\n");
+                    self.client.request(&EditMessageText {
                         chat_id,
-                        video: &cached_id,
-                        caption: &self.caption,
-                        reply_to_message_id: Some(msg_id),
-                    }).right_future(),
-                },
+                        message_id,
+                        text,
+                        ..default()
+                    }).await?;
+                }
+            }
+
+            Err(Ok(cached_id)) => _ = try_join! {
+                self.send_media(reply_to, chat_id, options.format, caption, &cached_id, None),
                 self.client.request(&DeleteMessage { chat_id, message_id }),
             }?,
 
-            Err(Err(download::Error::TooLarge)) => {
+            Err(Err(err)) => {
                 self.client.request(&EditMessageText {
                     chat_id,
                     message_id,
-                    text: match mkind {
-                        download::MediaKind::Video => "The video is too large",
-                        download::MediaKind::Audio => "The track is too large",
-                    },
+                    text: lang.error(&err, mkind),
+                    ..default()
                 }).await?;
             }
+        }
 
-            Err(Err(download::Error::IsStream)) => {
-                self.client.request(&EditMessageText {
-                    chat_id,
-                    message_id,
-                    text: "Live streams can't be downloaded while they're ongoing",
-                }).await?;
-            }
+        Ok(())
+    }
 
-            Err(Err(download::Error::NotFound | download::Error::InvalidLink)) => {
-                self.client.request(&EditMessageText {
-                    chat_id,
-                    message_id,
-                    text: "The provided link doesn't point to an existing video/track.\n\
-                           Make sure the link is copied correctly and try again.\n\
-                           Keep in mind that shortened links are not accepted."
-                }).await?;
-            }
+    /// Returns the ID of the sent message & the Telegram file ID of the media.
+    async fn upload_media(
+        &self,
+        reply_to: Option<i32>,
+        chat_id: i64,
+        format: OutputFormat,
+        caption: &str,
+        mut media: download::Media,
+    ) -> Result<(i32, Box<str>)> {
+        let format = media.format().unwrap_or(format);
+        let size = media.size_hint().0 as u64;
+        let filename = take(media.filename_mut());
+        let payload = Part::stream_with_length(Body::wrap_stream(media), size)
+            .file_name(filename)
+            .mime_str(format.mime_type())?;
 
-            Err(Err(download::Error::DataFetchFailed | download::Error::MetadataFetchFailed)) => {
-                self.client.request(&EditMessageText {
-                    chat_id,
-                    message_id,
-                    text: "An unexpected error occured while downloading",
-                }).await?;
-            }
+        let msg = self
+            .send_media(reply_to, chat_id, format, caption, "attach://payload", Some(payload))
+            .await?;
+        let MessageKind::Common(common) = msg.kind;
+        match common.media_kind {
+            | MediaKind::Audio { audio: file }
+            | MediaKind::Video { video: file }
+            | MediaKind::Document { document: file } => Ok((msg.id, file.id)),
+            MediaKind::Text { .. } => Err(io::Error::other("unexpected media kind"))?,
         }
+    }
 
-        Ok(())
+    /// `file` is either a Telegram file ID or `attach://payload` if `payload` is provided.
+    /// MP3, M4A & MP4 are sent as audio/video; other formats can't be played by Telegram inline,
+    /// so they're sent as documents.
+    async fn send_media(
+        &self,
+        reply_to_message_id: Option<i32>,
+        chat_id: i64,
+        format: OutputFormat,
+        caption: &str,
+        file: &str,
+        payload: Option<Part>,
+    ) -> Result<Message> {
+        match format {
+            OutputFormat::Mp3 | OutputFormat::M4a => self.send(&SendAudio {
+                chat_id, audio: file, caption, reply_to_message_id,
+            }, payload).await,
+            OutputFormat::Mp4 => self.send(&SendVideo {
+                chat_id, video: file, caption, reply_to_message_id,
+            }, payload).await,
+            _ => self.send(&SendDocument {
+                chat_id, document: file, caption, reply_to_message_id,
+            }, payload).await,
+        }
+    }
+
+    async fn send<R>(&self, req: &R, payload: Option<Part>) -> Result<Message>
+    where
+        R: telegram::Request<Response = Message> + Debug + Sync,
+    {
+        match payload {
+            Some(payload) => self.client.multipart_request(req, payload).await,
+            None => self.client.request(req).await,
+        }
+    }
+}
+
+fn callback_button(text: String, data: String) -> InlineKeyboardButton {
+    InlineKeyboardButton {
+        text: text.into(),
+        kind: InlineKeyboardButtonKind::CallbackData(data.into()),
+    }
+}
+
+/// Lays out the buttons, given as pairs of text & callback data, in rows of
+/// [`KEYBOARD_ROW_LEN`].
+fn keyboard(buttons: impl IntoIterator<Item = (String, String)>) -> InlineKeyboardMarkup {
+    let mut buttons = buttons.into_iter()
+        .map(|(text, data)| callback_button(text, data))
+        .peekable();
+    let mut inline_keyboard = vec![];
+    while buttons.peek().is_some() {
+        inline_keyboard.push(buttons.by_ref().take(KEYBOARD_ROW_LEN).collect());
+    }
+    InlineKeyboardMarkup { inline_keyboard }
+}
+
+/// The buttons of the settings menu, one per setting, showing its current value.
+fn settings_menu(lang: &Lang, user_id: u64, settings: &UserSettings) -> InlineKeyboardMarkup {
+    let options = |mkind| match settings.args(mkind) {
+        "" => lang.default_options,
+        args => args,
+    };
+    let caption = if settings.caption { lang.on } else { lang.off };
+    let lang_name = settings.lang.as_deref()
+        .and_then(Lang::get)
+        .map_or(lang.auto_language, |lang| lang.name);
+    let rows = [
+        (format!("{}: {}", lang.video, options(download::MediaKind::Video)), "video"),
+        (format!("{}: {}", lang.audio, options(download::MediaKind::Audio)), "audio"),
+        (format!("{}: {caption}", lang.caption), "caption"),
+        (format!("{}: {lang_name}", lang.language), "lang"),
+    ];
+    InlineKeyboardMarkup {
+        inline_keyboard: rows.into_iter()
+            .map(|(text, setting)| {
+                vec![callback_button(text, format!("settings {user_id} {setting}"))]
+            })
+            .collect(),
     }
 }
 
-pub async fn init(stats: Stats) -> Result<Arc<Bot>> {
-    Bot::new(stats).await.map(Arc::new)
+/// Only the whole media in its best quality is cached.
+fn cacheable(options: download::Options) -> bool {
+    options.quality == download::Quality::Best && options.clip.is_full()
+}
+
+pub async fn init(config: &Config, stats: Stats, downloader: Arc<download::Downloader>)
+    -> Result<Arc<Bot>>
+{
+    let bot = Arc::new(Bot::new(config, stats, downloader).await?);
+    if bot.updates == UpdateMode::Polling {
+        let poller = tokio::spawn(bot.clone().poll());
+        *bot.poller.lock().unwrap_or_else(PoisonError::into_inner) = Some(poller);
+    }
+    Ok(bot)
 }
 
 pub async fn deinit(bot: Arc<Bot>) -> Result {
+    let poller = bot.poller.lock().unwrap_or_else(PoisonError::into_inner).take();
+    if let Some(poller) = poller {
+        poller.abort();
+        _ = poller.await;
+    }
+    bot.tasks.close();
+    if !bot.tasks.is_empty() {
+        log::info!("Waiting for {} updates to be processed", bot.tasks.len());
+    }
+    bot.tasks.wait().await;
     try_join! {
         bot.cache.sync(),
-        bot.client.request(&DeleteWebhook),
+        async {
+            if bot.updates == UpdateMode::Webhook {
+                bot.client.request(&DeleteWebhook { drop_pending_updates: false }).await?;
+            }
+            Ok(())
+        },
         bot.client.request(&SendMessage { chat_id: bot.owner_id, text: "OFF", ..default() }),
     }?;
     bot.is_active.store(false, Relaxed);
@@ -319,8 +1132,30 @@ pub async fn deinit(bot: Arc<Bot>) -> Result {
     Ok(())
 }
 
-pub async fn handle_update(state: State<Arc<Bot>>, update: Json<Update>) {
-    if let Err(err) = state.handle_update(&update).await {
-        log::error!("Telegram bot error\nUpdate: {update:#?}\nError: {err}");
+/// Compares the secrets in constant time, so that the timing doesn't reveal how much of the
+/// secret was guessed correctly.
+fn secret_matches(secret: &[u8], expected: &[u8]) -> bool {
+    secret.len() == expected.len()
+        && secret.iter().zip(expected).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub async fn handle_update(
+    state: State<Arc<Bot>>,
+    addr: ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    update: Result<Json<Update>, JsonRejection>,
+) -> StatusCode {
+    // Checked before the body, so that all the forged requests get reported.
+    let secret = headers.get(SECRET_TOKEN_HEADER).map_or(&[][..], |x| x.as_bytes());
+    if !secret_matches(secret, state.webhook_secret.as_bytes()) {
+        state.report_forged_update(addr.0);
+        return StatusCode::UNAUTHORIZED;
+    }
+    // Telegram redelivers the updates that aren't answered soon enough, or that are answered
+    // with an error, so the ones that can't be decoded are skipped, as when polling.
+    match update {
+        Ok(Json(update)) => state.spawn(update),
+        Err(err) => log::warn!("Skipping an update: {err}"),
     }
+    StatusCode::OK
 }
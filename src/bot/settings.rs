@@ -0,0 +1,99 @@
+use {
+    crate::{download::{MediaKind, Options}, utils::{default, Result}},
+    serde::{Deserialize, Serialize},
+    std::{collections::HashMap, fs::File, io::ErrorKind::NotFound},
+    tokio::{fs, sync::RwLock},
+};
+
+/// Preferences of a user, applied when a command or a link doesn't specify them.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct UserSettings {
+    /// Options applied to videos as bot command arguments, e.g. "webm 720p"; empty if not set.
+    pub video: Box<str>,
+    /// Options applied to audio, like `video`.
+    pub audio: Box<str>,
+    /// Whether the bot's username is put in the captions of the media.
+    pub caption: bool,
+    /// Code of the language of the bot's messages; the language of the user's Telegram client is
+    /// used if not set.
+    pub lang: Option<Box<str>>,
+}
+
+impl Default for UserSettings {
+    fn default() -> Self {
+        Self { video: default(), audio: default(), caption: true, lang: None }
+    }
+}
+
+impl UserSettings {
+    pub fn args(&self, mkind: MediaKind) -> &str {
+        match mkind {
+            MediaKind::Video => &self.video,
+            MediaKind::Audio => &self.audio,
+        }
+    }
+
+    pub const fn args_mut(&mut self, mkind: MediaKind) -> &mut Box<str> {
+        match mkind {
+            MediaKind::Video => &mut self.video,
+            MediaKind::Audio => &mut self.audio,
+        }
+    }
+
+    /// The options to download media of `mkind` with, unless others are specified.
+    pub fn options(&self, mkind: MediaKind) -> Options {
+        let mut res = Options::new(mkind);
+        for arg in self.args(mkind).split_whitespace() {
+            res.parse_arg(arg);
+        }
+        res
+    }
+}
+
+/// Stored next to [`super::cache::Cache`].
+pub struct Settings {
+    /// Maps Telegram user IDs to their settings; users with the default settings are omitted.
+    inner: RwLock<HashMap<u64, UserSettings>>,
+    path: String,
+}
+
+impl Settings {
+    /// `cache_dir` is expected to have a trailing slash.
+    pub fn new(cache_dir: &str) -> Result<Self> {
+        let path = format!("{cache_dir}user_settings.json");
+        let inner = match File::open(&path) {
+            Ok(file) => serde_json::from_reader(file)?,
+            Err(e) if e.kind() == NotFound => default(),
+            Err(e) => Err(e)?,
+        };
+        Ok(Self { inner: RwLock::new(inner), path })
+    }
+
+    pub async fn get(&self, user_id: u64) -> UserSettings {
+        self.inner.read().await.get(&user_id).cloned().unwrap_or_default()
+    }
+
+    /// Changes the settings of the user & returns the new ones.
+    /// Unlike the cache, the settings can't be recreated, so they're saved right away, replacing
+    /// the previous version atomically.
+    pub async fn update(&self, user_id: u64, f: impl FnOnce(&mut UserSettings))
+        -> Result<UserSettings>
+    {
+        let mut inner = self.inner.write().await;
+        let mut settings = inner.get(&user_id).cloned().unwrap_or_default();
+        f(&mut settings);
+        if settings == UserSettings::default() {
+            inner.remove(&user_id);
+        } else {
+            inner.insert(user_id, settings.clone());
+        }
+        let data = serde_json::to_vec(&*inner)?;
+        // The lock is held until the file is replaced, so that the saves don't overlap
+        let tmp = format!("{}.part", self.path);
+        fs::write(&tmp, data).await?;
+        fs::rename(tmp, &self.path).await?;
+        drop(inner);
+        Ok(settings)
+    }
+}
@@ -1,23 +1,25 @@
 use {
     crate::utils::Result,
-    reqwest::multipart::{Form, Part},
+    reqwest::{multipart::{Form, Part}, RequestBuilder},
     serde::{de::DeserializeOwned, ser::{Impossible, SerializeStruct}, Deserialize, Serialize, Serializer},
+    serde_json::Value,
     std::{fmt::Debug, future::Future},
 };
 
+#[cfg(feature = "telegram-logs")]
 pub const MAX_MSG_LEN: usize = 4096;
 
 pub trait Request: Serialize {
     const NAME: &str;
-    const URL: &str;
     type Response: DeserializeOwned;
 }
 
 #[derive(Debug, Serialize)]
-pub struct SetWebhook<'url, 'secret_token> {
+pub struct SetWebhook<'url, 'secret_token, 'allowed_updates> {
     pub url: &'url str,
     pub drop_pending_updates: bool,
     pub secret_token: Option<&'secret_token str>,
+    pub allowed_updates: &'allowed_updates [&'allowed_updates str],
 }
 
 #[derive(Debug, Serialize)]
@@ -45,6 +47,9 @@ impl Update {
     pub const fn from(&self) -> Option<&User> {
         match &self.kind {
             UpdateKind::Message(m) => m.from.as_ref(),
+            UpdateKind::InlineQuery(q) => Some(&q.from),
+            UpdateKind::ChosenInlineResult(r) => Some(&r.from),
+            UpdateKind::CallbackQuery(q) => Some(&q.from),
         }
     }
 }
@@ -53,6 +58,36 @@ impl Update {
 #[serde(rename_all = "snake_case")]
 pub enum UpdateKind {
     Message(Message),
+    InlineQuery(InlineQuery),
+    /// Only sent if inline feedback is enabled for the bot via `@BotFather`.
+    ChosenInlineResult(ChosenInlineResult),
+    CallbackQuery(CallbackQuery),
+}
+
+/// Sent when a button with `callback_data` is pressed.
+#[derive(Debug, Deserialize)]
+pub struct CallbackQuery {
+    pub id: Box<str>,
+    pub from: User,
+    /// The message with the button, if it was sent by the bot.
+    pub message: Option<Message>,
+    pub data: Option<Box<str>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InlineQuery {
+    pub id: Box<str>,
+    pub from: User,
+    pub query: Box<str>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChosenInlineResult {
+    pub result_id: Box<str>,
+    pub from: User,
+    pub query: Box<str>,
+    /// Only present if the result has an inline keyboard attached.
+    pub inline_message_id: Option<Box<str>>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -61,6 +96,8 @@ pub struct Message {
     pub id: i32,
     pub from: Option<User>,
     pub chat: Chat,
+    #[serde(rename = "reply_to_message")]
+    pub reply_to: Option<Box<Self>>,
     #[serde(flatten)]
     pub kind: MessageKind,
 }
@@ -93,6 +130,9 @@ pub enum MediaKind {
     Video {
         video: File,
     },
+    Document {
+        document: File,
+    },
 }
 
 #[derive(Debug, Deserialize)]
@@ -103,10 +143,35 @@ pub struct MessageEntity {
     pub kind: MessageEntityKind,
 }
 
+impl MessageEntity {
+    /// Returns the part of the message's `text` covered by the entity, whose bounds are in UTF-16
+    /// code units.
+    pub fn text<'text>(&self, text: &'text str) -> Option<&'text str> {
+        let (mut start, mut end, mut utf16_index) = (None, None, 0);
+        for (index, ch) in text.char_indices().chain([(text.len(), '\0')]) {
+            if utf16_index == self.offset {
+                start = Some(index);
+            }
+            if utf16_index == self.offset + self.length {
+                end = Some(index);
+                break;
+            }
+            utf16_index += ch.len_utf16();
+        }
+        text.get(start?..end?)
+    }
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum MessageEntityKind {
     BotCommand,
+    /// `@username`
+    Mention,
+    /// The link is the text of the entity.
+    Url,
+    /// A link behind a piece of text.
+    TextLink { url: Box<str> },
     #[serde(other)]
     Other,
 }
@@ -150,6 +215,7 @@ pub enum ChatKind {
 pub enum PublicChatKind {
     Channel,
     Group,
+    #[serde(rename = "supergroup")]
     SuperGroup,
 }
 
@@ -161,6 +227,8 @@ pub struct SendMessage<'text> {
     pub disable_web_page_preview: bool, 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub reply_to_message_id: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reply_markup: Option<InlineKeyboardMarkup>,
 }
 
 #[derive(Debug, Default, Serialize)]
@@ -181,11 +249,104 @@ pub struct SendVideo<'video, 'caption> {
     pub reply_to_message_id: Option<i32>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Default, Serialize)]
+pub struct SendDocument<'document, 'caption> {
+    pub chat_id: i64,
+    pub document: &'document str,
+    pub caption: &'caption str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reply_to_message_id: Option<i32>,
+}
+
+#[derive(Debug, Default, Serialize)]
 pub struct EditMessageText<'text> {
     pub chat_id: i64,
     pub message_id: i32,
     pub text: &'text str,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub disable_web_page_preview: bool,
+    /// The keyboard is removed if not set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reply_markup: Option<InlineKeyboardMarkup>,
+}
+
+/// Only the messages sent via inline mode are supported.
+#[derive(Debug, Serialize)]
+pub struct EditMessageCaption<'inline_message_id, 'caption> {
+    pub inline_message_id: &'inline_message_id str,
+    pub caption: &'caption str,
+}
+
+/// Only the messages sent via inline mode are supported.
+#[derive(Debug, Serialize)]
+pub struct EditMessageMedia<'inline_message_id, 'media> {
+    pub inline_message_id: &'inline_message_id str,
+    pub media: InputMedia<'media>,
+}
+
+/// `media` is a Telegram file ID, since new files can't be uploaded to the messages sent via
+/// inline mode.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum InputMedia<'media> {
+    Audio { media: &'media str, caption: &'media str },
+    Video { media: &'media str, caption: &'media str },
+    Document { media: &'media str, caption: &'media str },
+}
+
+#[derive(Debug, Serialize)]
+pub struct AnswerInlineQuery<'inline_query_id, 'results> {
+    pub inline_query_id: &'inline_query_id str,
+    pub results: &'results [InlineQueryResult],
+    /// In seconds.
+    pub cache_time: u32,
+    pub is_personal: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum InlineQueryResult {
+    /// A previously sent audio file.
+    Audio { id: Box<str>, audio_file_id: Box<str>, caption: Box<str> },
+    /// A previously sent video file.
+    Video { id: Box<str>, video_file_id: Box<str>, title: Box<str>, caption: Box<str> },
+    /// A previously sent file of any kind.
+    Document {
+        id: Box<str>,
+        title: Box<str>,
+        document_file_id: Box<str>,
+        description: Box<str>,
+        caption: Box<str>,
+        reply_markup: InlineKeyboardMarkup,
+    },
+}
+
+#[derive(Debug, Serialize)]
+pub struct InlineKeyboardMarkup {
+    pub inline_keyboard: Vec<Vec<InlineKeyboardButton>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InlineKeyboardButton {
+    pub text: Box<str>,
+    #[serde(flatten)]
+    pub kind: InlineKeyboardButtonKind,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InlineKeyboardButtonKind {
+    Url(Box<str>),
+    /// Sent back in a [`CallbackQuery`] when the button is pressed; 1 to 64 bytes.
+    CallbackData(Box<str>),
+}
+
+#[derive(Debug, Serialize)]
+pub struct AnswerCallbackQuery<'callback_query_id, 'text> {
+    pub callback_query_id: &'callback_query_id str,
+    /// Shown to the user as a notification.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<&'text str>,
 }
 
 #[derive(Debug, Serialize)]
@@ -198,20 +359,29 @@ pub struct DeleteMessage {
 pub struct GetMe;
 
 #[derive(Debug, Serialize)]
-pub struct DeleteWebhook;
+pub struct DeleteWebhook {
+    pub drop_pending_updates: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GetUpdates<'allowed_updates> {
+    /// ID of the first update to be returned; all the updates before it are confirmed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offset: Option<u64>,
+    /// In seconds.
+    pub timeout: u32,
+    pub allowed_updates: &'allowed_updates [&'allowed_updates str],
+}
+
+/// Raw updates, since an update that can't be deserialised into [`Update`] mustn't make the
+/// others undeliverable.
+pub type Updates = Box<[Value]>;
 
 macro_rules! impl_request {
     ($($req:ident $(<$($arg:tt),+>)? => $resp:ident)+) => {
         $(
             impl Request for $req $(<$($arg),+>)? {
                 const NAME: &'static str = stringify!($req);
-                const URL: &'static str = concat!(
-                    env!("TELEGRAM_API_URL"),
-                    "/bot",
-                    env!("BOT_TOKEN"),
-                    "/",
-                    stringify!($req),
-                );
                 type Response = $resp;
             }
         )+
@@ -219,15 +389,21 @@ macro_rules! impl_request {
 }
 
 impl_request! {
-    SetWebhook<'_, '_> => bool
+    SetWebhook<'_, '_, '_> => bool
     DeleteWebhook => bool
+    GetUpdates<'_> => Updates
     SetMyCommands<'_, '_> => bool
     SendMessage<'_> => Message
     GetMe => User
     SendAudio<'_, '_> => Message
     SendVideo<'_, '_> => Message
+    SendDocument<'_, '_> => Message
     EditMessageText<'_> => Message
     DeleteMessage => bool
+    EditMessageMedia<'_, '_> => bool
+    EditMessageCaption<'_, '_> => bool
+    AnswerInlineQuery<'_, '_> => bool
+    AnswerCallbackQuery<'_, '_> => bool
 }
 
 #[derive(Deserialize)]
@@ -360,17 +536,34 @@ fn serialise_into_form<R: Request>(req: &R) -> Result<Form> {
         .map_err(|_| format!("{} can't be represented as multipart/form-data", R::NAME).into())
 }
 
-#[derive(Default)]
+/// Unlike in a plain `reqwest` call, the URL is left out of the error, since it contains the
+/// bot's token.
+async fn send<T: DeserializeOwned>(req: RequestBuilder) -> reqwest::Result<TelegramResponse<T>> {
+    async { req.send().await?.json().await }.await.map_err(reqwest::Error::without_url)
+}
+
 pub struct Client {
     inner: reqwest::Client,
+    /// The URL of the bot's API methods without the method name, with a trailing slash.
+    url: Box<str>,
 }
 
 impl Client {
+    /// `api_url` is the URL of the Bot API server without a trailing slash,
+    /// e.g. `https://api.telegram.org`
+    pub fn new(api_url: &str, token: &str) -> Self {
+        Self { inner: reqwest::Client::new(), url: format!("{api_url}/bot{token}/").into() }
+    }
+
+    fn url<R: Request>(&self) -> String {
+        format!("{}{}", self.url, R::NAME)
+    }
+
     pub fn request<R: Request + Debug>(&self, req: &R) -> impl Future<Output = Result<R::Response>> + Send {
         log::info!("About to send to Telegram: {req:#?}");
-        let req = self.inner.get(R::URL).json(req);
+        let req = self.inner.get(self.url::<R>()).json(req);
         async move {
-            match req.send().await?.json().await? {
+            match send(req).await? {
                 TelegramResponse { ok: true, result: Some(result), .. } => Ok(result),
                 TelegramResponse { mut description, .. } => Err({
                     description.insert_str(0, ": Telegram API error: ");
@@ -387,10 +580,10 @@ impl Client {
     {
         log::info!("About to send to Telegram: {req:#?}");
         let req = serialise_into_form(req)
-            .map(|form| self.inner.get(R::URL).multipart(form.part("payload", payload)));
+            .map(|form| self.inner.get(self.url::<R>()).multipart(form.part("payload", payload)));
 
         async move {
-            match req?.send().await?.json().await? {
+            match send(req?).await? {
                 TelegramResponse { ok: true, result: Some(result), .. } => Ok(result),
                 TelegramResponse { mut description, .. } => Err({
                     description.insert_str(0, ": Telegram API error: ");
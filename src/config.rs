@@ -0,0 +1,329 @@
+//! Settings read at startup from a TOML file, see `config.example.toml`, & from the environment.
+
+use {
+    crate::utils::Result,
+    reqwest::Url,
+    serde::Deserialize,
+    std::{
+        env::{self, VarError},
+        fmt::Display,
+        io::ErrorKind::NotFound,
+        net::{Ipv4Addr, SocketAddr},
+        str::FromStr,
+        time::Duration,
+    },
+};
+
+/// Used if `CONFIG` isn't set.
+const DEFAULT_PATH: &str = "config.toml";
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Public URL of the server, without a trailing slash; required only by the bot.
+    pub url: String,
+    pub bind: SocketAddr,
+    /// Directory for the caches & temporary files, with a trailing slash.
+    pub cache_dir: String,
+    pub website: Website,
+    pub telegram: Telegram,
+    pub log: Log,
+    pub download: Download,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            bind: SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 8443),
+            cache_dir: "cache/".to_owned(),
+            website: Website::default(),
+            telegram: Telegram::default(),
+            log: Log::default(),
+            download: Download::default(),
+        }
+    }
+}
+
+/// The pages & the download endpoints.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Website {
+    /// Enabled by default if the `website` feature is.
+    pub enabled: bool,
+}
+
+#[allow(clippy::derivable_impls, reason = "the default depends on the features")]
+impl Default for Website {
+    fn default() -> Self {
+        Self { enabled: cfg!(feature = "website") }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Telegram {
+    /// Whether to run the bot; enabled by default if the `bot` feature is.
+    pub enabled: bool,
+    pub updates: UpdateMode,
+    pub api_url: String,
+    pub token: String,
+    /// Receives the logs & has access to the admin commands.
+    pub owner_id: i64,
+    /// Sent by Telegram with every update in [`UpdateMode::Webhook`], to tell the genuine updates
+    /// from forged ones; generated at startup if not set.
+    pub webhook_secret: Option<String>,
+}
+
+impl Default for Telegram {
+    fn default() -> Self {
+        Self {
+            enabled: cfg!(feature = "bot"),
+            updates: UpdateMode::Webhook,
+            api_url: "https://api.telegram.org".to_owned(),
+            token: String::new(),
+            owner_id: 0,
+            webhook_secret: None,
+        }
+    }
+}
+
+/// How the bot receives the updates from Telegram.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpdateMode {
+    /// Telegram sends the updates to `{url}/bot`, which has to be reachable via HTTPS.
+    Webhook,
+    /// The bot requests the updates via `getUpdates`, which works behind NAT too.
+    Polling,
+}
+
+impl FromStr for UpdateMode {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "webhook" => Ok(Self::Webhook),
+            "polling" => Ok(Self::Polling),
+            _ => Err("expected `webhook` or `polling`"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Log {
+    /// Whether to send the logs to the bot's owner, if the bot is running; enabled by default if
+    /// the `telegram-logs` feature is.
+    pub telegram: bool,
+    /// The logs are appended to this file if they're not sent via Telegram; stderr is used if
+    /// it's not set.
+    pub file: Option<String>,
+}
+
+#[allow(clippy::derivable_impls, reason = "the default depends on the features")]
+impl Default for Log {
+    fn default() -> Self {
+        Self { telegram: cfg!(feature = "telegram-logs"), file: None }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Download {
+    /// In bytes.
+    pub max_filesize: usize,
+    /// How long a single invocation of `yt-dlp` may take, in seconds.
+    pub yt_dlp_timeout: u64,
+    /// Base URLs of the Piped API, tried in rotation.
+    pub piped_instances: Vec<String>,
+    pub max_jobs: usize,
+    pub max_queued: usize,
+    pub max_playlist_entries: usize,
+    /// In bytes; twice `max_filesize` if not set.
+    pub max_playlist_size: Option<usize>,
+    /// In bytes.
+    pub cache_max_size: usize,
+    /// In seconds.
+    pub cache_ttl: u64,
+}
+
+impl Default for Download {
+    fn default() -> Self {
+        Self {
+            max_filesize: 1 << 30,
+            yt_dlp_timeout: 30 * 60,
+            piped_instances: vec![
+                "https://pipedapi.kavin.rocks".to_owned(),
+                "https://pipedapi.r4fo.com".to_owned(),
+            ],
+            max_jobs: 4,
+            max_queued: 16,
+            max_playlist_entries: 50,
+            max_playlist_size: None,
+            cache_max_size: 10 << 30,
+            cache_ttl: 7 * 24 * 60 * 60,
+        }
+    }
+}
+
+impl Download {
+    pub fn max_playlist_size(&self) -> usize {
+        self.max_playlist_size.unwrap_or(self.max_filesize.saturating_mul(2))
+    }
+
+    pub const fn yt_dlp_timeout(&self) -> Duration {
+        Duration::from_secs(self.yt_dlp_timeout)
+    }
+
+    pub const fn cache_ttl(&self) -> Duration {
+        Duration::from_secs(self.cache_ttl)
+    }
+}
+
+/// Overrides `dst` with the value of the environment variable `name`, if it's set.
+fn var<T: FromStr>(name: &str, dst: &mut T) -> Result
+where
+    T::Err: Display,
+{
+    match env::var(name) {
+        Ok(value) => *dst = value.parse().map_err(|e| format!("invalid ${name}: {e}"))?,
+        Err(VarError::NotPresent) => (),
+        Err(e) => Err(format!("invalid ${name}: {e}"))?,
+    }
+    Ok(())
+}
+
+/// Generates a secret of 32 alphanumeric characters using the OS's random number generator.
+fn random_secret() -> Result<String> {
+    const LEN: usize = 32;
+    const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+    // The bytes past the largest multiple of the charset's length are skipped, so that all the
+    // characters are equally likely.
+    const LIMIT: usize = 256 / CHARSET.len() * CHARSET.len();
+    let (mut res, mut buf) = (String::with_capacity(LEN), [0; LEN * 2]);
+    while res.len() < LEN {
+        getrandom::getrandom(&mut buf)?;
+        let chars = buf.iter()
+            .map(|&byte| usize::from(byte))
+            .filter(|&byte| byte < LIMIT)
+            .map(|byte| char::from(CHARSET[byte % CHARSET.len()]));
+        res.extend(chars.take(LEN - res.len()));
+    }
+    Ok(res)
+}
+
+impl Config {
+    /// Reads the file at `$CONFIG`, or `config.toml` if it's not set, then applies the overrides
+    /// from the environment; the file is optional unless `$CONFIG` is set.
+    pub fn load() -> Result<Self> {
+        let (path, required) = match env::var("CONFIG") {
+            Ok(path) => (path, true),
+            Err(_) => (DEFAULT_PATH.to_owned(), false),
+        };
+        let mut res: Self = match std::fs::read_to_string(&path) {
+            Ok(src) => toml::from_str(&src).map_err(|e| format!("{path}: {e}"))?,
+            Err(e) if e.kind() == NotFound && !required => Self::default(),
+            Err(e) => Err(format!("{path}: {e}"))?,
+        };
+
+        var("ENABLE_WEBSITE", &mut res.website.enabled)?;
+        var("ENABLE_BOT", &mut res.telegram.enabled)?;
+        var("BOT_UPDATES", &mut res.telegram.updates)?;
+        var("LOG_TO_TELEGRAM", &mut res.log.telegram)?;
+        if let Ok(path) = env::var("LOG_FILE") {
+            res.log.file = Some(path);
+        }
+        var("URL", &mut res.url)?;
+        var("BIND_ADDR", &mut res.bind)?;
+        var("CACHE_DIR", &mut res.cache_dir)?;
+        var("TELEGRAM_API_URL", &mut res.telegram.api_url)?;
+        var("BOT_TOKEN", &mut res.telegram.token)?;
+        var("OWNER_TELEGRAM_ID", &mut res.telegram.owner_id)?;
+        if let Ok(secret) = env::var("WEBHOOK_SECRET") {
+            res.telegram.webhook_secret = Some(secret);
+        }
+        var("MAX_FILESIZE", &mut res.download.max_filesize)?;
+        var("YT_DLP_TIMEOUT", &mut res.download.yt_dlp_timeout)?;
+        var("MAX_JOBS", &mut res.download.max_jobs)?;
+        var("MAX_QUEUED", &mut res.download.max_queued)?;
+        if let Ok(instances) = env::var("PIPED_INSTANCES") {
+            res.download.piped_instances = instances.split(',').map(str::to_owned).collect();
+        }
+
+        res.validate().map_err(|e| format!("{path}: {e}"))?;
+        Ok(res)
+    }
+
+    /// Checks the values & normalises the URLs & paths.
+    fn validate(&mut self) -> Result {
+        if self.website.enabled && !cfg!(feature = "website") {
+            Err("the website is enabled, but the `website` feature isn't")?;
+        }
+        if self.telegram.enabled && !cfg!(feature = "bot") {
+            Err("the bot is enabled, but the `bot` feature isn't")?;
+        }
+        if !self.website.enabled && !self.telegram.enabled {
+            Err("neither the website nor the bot is enabled")?;
+        }
+        self.log.telegram &= cfg!(feature = "telegram-logs") && self.telegram.enabled;
+
+        self.url.truncate(self.url.trim_end_matches('/').len());
+        if self.url.is_empty() && self.telegram.enabled
+            && self.telegram.updates == UpdateMode::Webhook
+        {
+            Err("`url` must be set for the bot's webhook, or $URL")?;
+        }
+        match Url::parse(&self.url) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => (),
+            Ok(_) => Err("`url` must be an HTTP(S) URL")?,
+            Err(_) if self.url.is_empty() => (),
+            Err(e) => Err(format!("invalid `url`: {e}"))?,
+        }
+
+        if self.cache_dir.is_empty() {
+            Err("`cache_dir` must not be empty")?;
+        }
+        if !self.cache_dir.ends_with('/') {
+            self.cache_dir.push('/');
+        }
+
+        let telegram = &mut self.telegram;
+        if telegram.enabled {
+            telegram.api_url.truncate(telegram.api_url.trim_end_matches('/').len());
+            Url::parse(&telegram.api_url)
+                .map_err(|e| format!("invalid `telegram.api_url`: {e}"))?;
+            if telegram.token.is_empty() {
+                Err("`telegram.token` must be set, or $BOT_TOKEN")?;
+            }
+            if telegram.token.contains(['/', '?', '#']) {
+                Err("`telegram.token` is malformed")?;
+            }
+            if telegram.owner_id == 0 {
+                Err("`telegram.owner_id` must be set, or $OWNER_TELEGRAM_ID")?;
+            }
+            match &telegram.webhook_secret {
+                // Telegram's requirements
+                Some(secret) if !(1..=256).contains(&secret.len())
+                    || !secret.bytes().all(|b| b.is_ascii_alphanumeric() || b"_-".contains(&b))
+                => Err("`telegram.webhook_secret` must consist of 1 to 256 characters A-Z, a-z, \
+                        0-9, `_` & `-`")?,
+                Some(_) => (),
+                None => telegram.webhook_secret = Some(random_secret()
+                    .map_err(|e| format!("failed to generate `telegram.webhook_secret`: {e}"))?),
+            }
+        }
+
+        let download = &self.download;
+        if download.max_filesize == 0 {
+            Err("`download.max_filesize` must be positive")?;
+        }
+        if download.max_jobs == 0 {
+            Err("`download.max_jobs` must be positive")?;
+        }
+        if download.yt_dlp_timeout == 0 {
+            Err("`download.yt_dlp_timeout` must be positive")?;
+        }
+        Ok(())
+    }
+}
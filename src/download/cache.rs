@@ -0,0 +1,298 @@
+use {
+    super::Media,
+    crate::utils::{default, Result},
+    axum::body::Bytes,
+    futures::{stream, StreamExt, TryStreamExt},
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::HashMap,
+        io::ErrorKind::NotFound,
+        sync::{Arc, Mutex, MutexGuard, PoisonError},
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    },
+    tokio::{
+        fs::{self, File},
+        io::AsyncWriteExt,
+    },
+    tokio_util::io::ReaderStream,
+};
+
+/// Bounds on what's kept in the media cache.
+#[derive(Debug, Clone, Copy)]
+pub struct CacheLimits {
+    /// Total size of the cached files; the least recently used ones are evicted past it.
+    pub max_size: usize,
+    /// How long a file is kept after being downloaded.
+    pub ttl: Duration,
+}
+
+impl Default for CacheLimits {
+    fn default() -> Self {
+        Self { max_size: 10 << 30, ttl: Duration::from_hours(24 * 7) }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct Entry {
+    /// The file is stored at `{MEDIA_DIR}{id}`.
+    id: u64,
+    filename: String,
+    size: usize,
+    /// Seconds since the Unix epoch.
+    created: u64,
+    /// Seconds since the Unix epoch.
+    last_used: u64,
+}
+
+#[derive(Deserialize, Serialize, Default)]
+struct Index {
+    next_id: u64,
+    /// Maps download keys, i.e. canonical links & options, to the cached files.
+    entries: HashMap<String, Entry>,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Downloaded media, stored under the cache directory and shared between the website & the bot.
+pub struct Cache {
+    /// With a trailing slash.
+    dir: String,
+    index: Mutex<Index>,
+    /// Held while saving the index, so that an older version of it doesn't overwrite a newer one.
+    saving: tokio::sync::Mutex<()>,
+    limits: CacheLimits,
+}
+
+impl Cache {
+    /// `cache_dir` is expected to have a trailing slash; the media is stored in a subdirectory.
+    pub fn new(cache_dir: &str, limits: CacheLimits) -> Result<Self> {
+        let dir = format!("{cache_dir}media/");
+        std::fs::create_dir_all(&dir)?;
+        let index = match std::fs::File::open(format!("{dir}index.json")) {
+            Ok(file) => serde_json::from_reader(file)?,
+            Err(e) if e.kind() == NotFound => default(),
+            Err(e) => Err(e)?,
+        };
+        Ok(Self { dir, index: Mutex::new(index), saving: default(), limits })
+    }
+
+    fn path(&self, id: u64) -> String {
+        format!("{}{id}", self.dir)
+    }
+
+    fn index_path(&self) -> String {
+        format!("{}index.json", self.dir)
+    }
+
+    fn index(&self) -> MutexGuard<'_, Index> {
+        self.index.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Writes the index to disk, replacing the previous version atomically.
+    async fn save(&self) {
+        let _saving = self.saving.lock().await;
+        let res: Result = async {
+            let index = serde_json::to_vec(&*self.index())?;
+            let tmp = format!("{}.part", self.index_path());
+            fs::write(&tmp, index).await?;
+            fs::rename(tmp, self.index_path()).await?;
+            Ok(())
+        }.await;
+        if let Err(err) = res {
+            log::error!("Failed to save the media cache index: {err}");
+        }
+    }
+
+    /// Removes the entries that are either expired or, if the cache is too large, least recently
+    /// used, apart from the one at `keep`, which may be empty.
+    async fn evict(&self, keep: &str) {
+        let evicted = {
+            let mut index = self.index();
+            let expired = now().saturating_sub(self.limits.ttl.as_secs());
+            let mut evicted: Vec<_> = index.entries
+                .extract_if(|key, entry| key != keep && entry.created < expired)
+                .collect();
+            let mut size: usize = index.entries.values().map(|entry| entry.size).sum();
+            if size > self.limits.max_size {
+                let mut lru: Vec<_> = index.entries.iter()
+                    .filter(|&(key, _)| key != keep)
+                    .map(|(key, entry)| (entry.last_used, key.clone()))
+                    .collect();
+                lru.sort_unstable();
+                for (_, key) in lru {
+                    if size <= self.limits.max_size {
+                        break;
+                    }
+                    if let Some(entry) = index.entries.remove(&key) {
+                        size -= entry.size;
+                        evicted.push((key, entry));
+                    }
+                }
+            }
+            evicted
+        };
+
+        for (key, entry) in &evicted {
+            log::info!("Evicting {key:?} from the media cache");
+            if let Err(err) = fs::remove_file(self.path(entry.id)).await {
+                log::error!("Failed to remove the cached file of {key:?}: {err}");
+            }
+        }
+        if !evicted.is_empty() {
+            self.save().await;
+        }
+    }
+
+    /// Returns the cached media at `key` unless it has expired.
+    pub async fn get(&self, key: &str) -> Option<Media> {
+        let (id, filename, size, expired) = {
+            let mut index = self.index();
+            let entry = index.entries.get_mut(key)?;
+            entry.last_used = now();
+            let expired = entry.created < entry.last_used.saturating_sub(self.limits.ttl.as_secs());
+            (entry.id, entry.filename.clone(), entry.size, expired)
+        };
+        if expired {
+            self.evict("").await;
+            return None;
+        }
+        let file = match File::open(self.path(id)).await {
+            Ok(file) => file,
+            Err(err) => {
+                log::error!("Failed to open the cached file of {key:?}: {err}");
+                self.index().entries.remove(key);
+                return None;
+            }
+        };
+        let mut media = Media::new(ReaderStream::new(file).map_err(Into::into), size);
+        media.filename = filename;
+        Some(media)
+    }
+
+    /// Stores `media` in the cache as it's being read, provided it's read to the end successfully.
+    pub fn tee(self: Arc<Self>, key: String, media: Media) -> Media {
+        let id = {
+            let mut index = self.index();
+            index.next_id += 1;
+            index.next_id
+        };
+        let (filesize, filename) = (media.filesize, media.filename.clone());
+        let writer = Writer {
+            cache: self,
+            key,
+            filename: filename.clone(),
+            id,
+            file: Target::Pending,
+            size: 0,
+        };
+        let chunks = stream::unfold((media, writer), |(mut media, mut writer)| async move {
+            let Some(chunk) = media.next().await else {
+                writer.commit().await;
+                return None;
+            };
+            match &chunk {
+                Ok(bytes) => writer.write(bytes).await,
+                Err(_) => writer.discard(),
+            }
+            Some((chunk, (media, writer)))
+        });
+        let mut media = Media::new(chunks, filesize);
+        media.filename = filename;
+        media
+    }
+}
+
+/// Writes media into a temporary file, which is moved into the cache once the media is complete.
+struct Writer {
+    cache: Arc<Cache>,
+    key: String,
+    filename: String,
+    id: u64,
+    file: Target,
+    size: usize,
+}
+
+enum Target {
+    /// The file is created on the first write.
+    Pending,
+    Open(File),
+    /// The media is either committed or discarded.
+    Closed,
+}
+
+impl Writer {
+    fn tmp_path(&self) -> String {
+        format!("{}.part", self.cache.path(self.id))
+    }
+
+    async fn write(&mut self, bytes: &Bytes) {
+        if matches!(self.file, Target::Pending) {
+            match File::create(self.tmp_path()).await {
+                Ok(file) => self.file = Target::Open(file),
+                Err(err) => {
+                    log::error!("Failed to cache {:?}: {err}", self.key);
+                    self.file = Target::Closed;
+                }
+            }
+        }
+        let Target::Open(file) = &mut self.file else { return };
+        if let Err(err) = file.write_all(bytes).await {
+            log::error!("Failed to cache {:?}: {err}", self.key);
+            return self.discard();
+        }
+        self.size += bytes.len();
+    }
+
+    /// Removes the temporary file, if any, in the background.
+    fn discard(&mut self) {
+        if let Target::Open(file) = std::mem::replace(&mut self.file, Target::Closed) {
+            let tmp = self.tmp_path();
+            tokio::spawn(async move {
+                drop(file);
+                if let Err(err) = fs::remove_file(&tmp).await {
+                    log::error!("Failed to remove {tmp:?}: {err}");
+                }
+            });
+        }
+    }
+
+    async fn commit(&mut self) {
+        let Target::Open(mut file) = std::mem::replace(&mut self.file, Target::Closed) else {
+            return;
+        };
+        let tmp = self.tmp_path();
+        let res = async {
+            file.flush().await?;
+            drop(file);
+            fs::rename(&tmp, self.cache.path(self.id)).await
+        }.await;
+        if let Err(err) = res {
+            log::error!("Failed to cache {:?}: {err}", self.key);
+            _ = fs::remove_file(tmp).await;
+            return;
+        }
+
+        let (time, key) = (now(), std::mem::take(&mut self.key));
+        let entry = Entry {
+            id: self.id,
+            filename: std::mem::take(&mut self.filename),
+            size: self.size,
+            created: time,
+            last_used: time,
+        };
+        let replaced = self.cache.index().entries.insert(key.clone(), entry);
+        if let Some(replaced) = replaced {
+            _ = fs::remove_file(self.cache.path(replaced.id)).await;
+        }
+        self.cache.evict(&key).await;
+        self.cache.save().await;
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        self.discard();
+    }
+}
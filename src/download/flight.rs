@@ -0,0 +1,329 @@
+use {
+    super::{Download, Error, Media},
+    axum::body::Bytes,
+    futures::{
+        channel::oneshot,
+        future::{FutureExt, Shared},
+        stream,
+        Future,
+        StreamExt,
+    },
+    std::{
+        collections::{HashMap, VecDeque},
+        sync::{Arc, Mutex, MutexGuard, PoisonError, Weak},
+    },
+    tokio::sync::Notify,
+};
+
+/// How many chunks the download may get ahead of the slowest of its receivers.
+const MAX_BUFFERED: usize = 64;
+
+/// Size & name of the media, or `None` if the download can't be shared, e.g. because the link
+/// points to a playlist.
+type Start = Result<Option<(usize, String)>, Error>;
+
+/// A download that others can attach to, receiving the same bytes, until it drops its first chunk.
+struct Flight {
+    start: Shared<oneshot::Receiver<Start>>,
+    state: Mutex<State>,
+    /// Notified whenever `state` changes.
+    notify: Notify,
+}
+
+#[derive(Default)]
+struct State {
+    /// The chunks yet to be received by some of the receivers, the first one being the chunk at
+    /// `offset`.
+    chunks: VecDeque<Bytes>,
+    /// The number of chunks received by all the receivers & dropped.
+    offset: usize,
+    /// Maps the IDs of the receivers to the indices of the next chunks they're to receive.
+    cursors: HashMap<usize, usize>,
+    next_id: usize,
+    /// `Some` once the download is over, with an error message if it failed.
+    end: Option<Result<(), String>>,
+}
+
+impl State {
+    /// Drops the chunks received by all the receivers.
+    fn trim(&mut self) {
+        let Some(&min) = self.cursors.values().min() else { return };
+        self.chunks.drain(.. min - self.offset);
+        self.offset = min;
+    }
+}
+
+impl Flight {
+    /// Returns the flight along with the receiver for whoever started the download.
+    fn new(start: oneshot::Receiver<Start>) -> (Arc<Self>, Receiver) {
+        let state = State { cursors: HashMap::from([(0, 0)]), next_id: 1, ..State::default() };
+        let flight = Arc::new(Self {
+            start: start.shared(),
+            state: Mutex::new(state),
+            notify: Notify::new(),
+        });
+        (flight.clone(), Receiver { flight, id: 0 })
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Waits until the download is no more than [`MAX_BUFFERED`] chunks ahead of its slowest
+    /// receiver. Returns `false` if no one is receiving the download anymore.
+    async fn wait_for_room(&self) -> bool {
+        loop {
+            let notified = self.notify.notified();
+            {
+                let state = self.state();
+                if state.cursors.is_empty() {
+                    return false;
+                }
+                if state.chunks.len() < MAX_BUFFERED {
+                    return true;
+                }
+            }
+            notified.await;
+        }
+    }
+}
+
+/// Receives the chunks of a [`Flight`]; they stop being kept for it once it's dropped.
+struct Receiver {
+    flight: Arc<Flight>,
+    id: usize,
+}
+
+impl Receiver {
+    /// Returns `None` if the download is too far along to be received from the start or has
+    /// failed already.
+    fn new(flight: Arc<Flight>) -> Option<Self> {
+        let id = {
+            let mut state = flight.state();
+            if state.offset > 0 || state.end.as_ref().is_some_and(Result::is_err) {
+                return None;
+            }
+            let id = state.next_id;
+            state.next_id += 1;
+            state.cursors.insert(id, 0);
+            id
+        };
+        Some(Self { flight, id })
+    }
+
+    /// Returns `None` once the download is over.
+    async fn recv(&self) -> Option<Result<Bytes, String>> {
+        loop {
+            let notified = self.flight.notify.notified();
+            {
+                let mut state = self.flight.state();
+                let cursor = state.cursors.get(&self.id).copied().unwrap_or_default();
+                if let Some(chunk) = state.chunks.get(cursor - state.offset).cloned() {
+                    state.cursors.insert(self.id, cursor + 1);
+                    state.trim();
+                    drop(state);
+                    self.flight.notify.notify_waiters();
+                    return Some(Ok(chunk));
+                }
+                match &state.end {
+                    Some(Ok(())) => return None,
+                    Some(Err(msg)) => return Some(Err(msg.clone())),
+                    None => (),
+                }
+            }
+            notified.await;
+        }
+    }
+
+    fn into_media(self, filesize: usize, filename: String) -> Media {
+        let chunks = stream::unfold(Some(self), |receiver| async move {
+            let receiver = receiver?;
+            Some(match receiver.recv().await? {
+                Ok(chunk) => (Ok(chunk), Some(receiver)),
+                Err(msg) => (Err(msg.into()), None),
+            })
+        });
+        let mut media = Media::new(chunks, filesize);
+        media.filename = filename;
+        media
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        {
+            let mut state = self.flight.state();
+            state.cursors.remove(&self.id);
+            state.trim();
+        }
+        self.flight.notify.notify_waiters();
+    }
+}
+
+/// In-flight downloads, keyed by the canonical link & the options.
+#[derive(Default)]
+pub struct Flights(Mutex<HashMap<String, Weak<Flight>>>);
+
+impl Flights {
+    /// Runs `download` unless an identical one has just started, in which case the media is
+    /// received from that one instead.
+    pub async fn run(
+        self: Arc<Self>,
+        key: String,
+        download: impl Future<Output = Result<Download, Error>> + Send,
+    ) -> Result<Download, Error> {
+        let (flight, origin) = {
+            let mut flights = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+            if let Some(flight) = flights.get(&key).and_then(Weak::upgrade) {
+                (flight, None)
+            } else {
+                let (tx, rx) = oneshot::channel();
+                let (flight, receiver) = Flight::new(rx);
+                flights.insert(key.clone(), Arc::downgrade(&flight));
+                (flight, Some((tx, receiver)))
+            }
+        };
+
+        let Some((tx, receiver)) = origin else {
+            // Registered before the download starts, so that none of the chunks are dropped
+            // before this receiver gets them.
+            let Some(receiver) = Receiver::new(flight) else {
+                log::info!("The ongoing download of {key:?} is too far along to attach to it");
+                return download.await;
+            };
+            log::info!("Attaching to the ongoing download of {key:?}");
+            return match receiver.flight.start.clone().await {
+                Ok(Ok(Some((filesize, filename)))) => {
+                    Ok(Download::Media(receiver.into_media(filesize, filename)))
+                }
+                Ok(Err(err)) => Err(err),
+                // The original download got cancelled or can't be shared.
+                Ok(Ok(None)) | Err(_) => download.await,
+            };
+        };
+
+        match download.await {
+            Ok(Download::Media(media)) => {
+                let (filesize, filename) = (media.filesize, media.filename.clone());
+                _ = tx.send(Ok(Some((filesize, filename.clone()))));
+                tokio::spawn(self.pump(key, flight, media));
+                Ok(Download::Media(receiver.into_media(filesize, filename)))
+            }
+            Ok(download) => {
+                _ = tx.send(Ok(None));
+                self.remove(&key, &flight);
+                Ok(download)
+            }
+            Err(err) => {
+                _ = tx.send(Err(err.clone()));
+                self.remove(&key, &flight);
+                Err(err)
+            }
+        }
+    }
+
+    /// Reads `media` into `flight`, keeping pace with the slowest receiver, until it's over or
+    /// until no one is receiving it anymore.
+    async fn pump(self: Arc<Self>, key: String, flight: Arc<Flight>, mut media: Media) {
+        let mut attachable = true;
+        let end = loop {
+            if !flight.wait_for_room().await {
+                log::info!("The download of {key:?} was abandoned");
+                break Err("abandoned".to_owned());
+            }
+            match media.next().await {
+                Some(Ok(chunk)) => flight.state().chunks.push_back(chunk),
+                Some(Err(err)) => break Err(err.to_string()),
+                None => break Ok(()),
+            }
+            flight.notify.notify_waiters();
+            if attachable && flight.state().offset > 0 {
+                // Let the identical downloads started from now on share a new flight instead.
+                attachable = false;
+                self.remove(&key, &flight);
+            }
+        };
+        drop(media);
+
+        flight.state().end = Some(end);
+        flight.notify.notify_waiters();
+        self.remove(&key, &flight);
+    }
+
+    /// Makes the identical downloads started afterwards not attach to `flight`.
+    fn remove(&self, key: &str, flight: &Arc<Flight>) {
+        let mut flights = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        if flights.get(key).is_some_and(|other| Weak::ptr_eq(other, &Arc::downgrade(flight))) {
+            flights.remove(key);
+        }
+    }
+}
+
+#[cfg(test)]
+#[expect(clippy::unwrap_used, reason = "panicking is how tests fail")]
+mod tests {
+    use {
+        super::*,
+        futures::{future, TryStreamExt},
+        std::sync::atomic::{AtomicUsize, Ordering::Relaxed},
+    };
+
+    const N_CHUNKS: usize = MAX_BUFFERED * 4;
+
+    /// Counts the chunks produced, to check how far ahead of its receivers the download gets.
+    fn media(produced: &Arc<AtomicUsize>) -> Download {
+        let produced = produced.clone();
+        let chunks = stream::iter(0..N_CHUNKS).map(move |i| {
+            produced.fetch_add(1, Relaxed);
+            Ok(Bytes::from(i.to_string()))
+        });
+        Download::Media(Media::new(chunks, N_CHUNKS))
+    }
+
+    async fn collect(download: Result<Download, Error>) -> Vec<Bytes> {
+        let Ok(Download::Media(media)) = download else { panic!("expected media") };
+        media.try_collect().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn shares_the_download() {
+        let (flights, produced) = (Arc::new(Flights::default()), Arc::default());
+        let (first, second) = future::join(
+            flights.clone().run("key".to_owned(), async { Ok(media(&produced)) }),
+            flights.clone().run("key".to_owned(), future::pending()),
+        ).await;
+        let (Ok(Download::Media(mut first)), Ok(Download::Media(second))) = (first, second) else {
+            panic!("expected media");
+        };
+
+        // The download doesn't get too far ahead of the receiver that's lagging behind.
+        first.next().await.unwrap().unwrap();
+        tokio::task::yield_now().await;
+        assert!(produced.load(Relaxed) <= MAX_BUFFERED + 1);
+
+        let (first, second) = future::try_join(
+            first.try_collect::<Vec<_>>(),
+            second.try_collect::<Vec<_>>(),
+        ).await.unwrap();
+        assert_eq!(first.len(), N_CHUNKS - 1);
+        assert_eq!(second.len(), N_CHUNKS);
+    }
+
+    #[tokio::test]
+    async fn late_arrivals_download_on_their_own() {
+        let (flights, produced) = (Arc::new(Flights::default()), Arc::default());
+        let Ok(Download::Media(mut first)) =
+            flights.clone().run("key".to_owned(), async { Ok(media(&produced)) }).await
+        else {
+            panic!("expected media");
+        };
+        first.next().await.unwrap().unwrap();
+        first.next().await.unwrap().unwrap();
+
+        let other = Arc::default();
+        let second = collect(flights.run("key".to_owned(), async { Ok(media(&other)) }).await);
+        assert_eq!(second.await.len(), N_CHUNKS);
+        assert_eq!(other.load(Relaxed), N_CHUNKS);
+        drop(first);
+    }
+}
@@ -1,57 +1,160 @@
+// Parts of the API are only used by either the website or the bot.
+#![cfg_attr(not(all(feature = "website", feature = "bot")), allow(dead_code))]
+
 mod yt_dlp;
-//mod piped;
+mod piped;
+mod scheduler;
+mod flight;
+mod cache;
+mod resolve;
+mod routes;
+#[cfg(feature = "website")]
+pub mod zip;
 
 use {
-    crate::utils::Result,
+    crate::{config::Config, utils::Result},
     axum::{body::Bytes, http::Uri},
-    futures::{Stream, StreamExt},
-    std::{fmt::{Display, Formatter}, pin::Pin, str::FromStr, task::{Context, Poll}},
+    futures::{
+        future::BoxFuture,
+        ready,
+        stream::{self, BoxStream},
+        FutureExt,
+        Stream,
+        StreamExt,
+        TryStreamExt,
+    },
+    serde::{Deserialize, Serialize},
+    std::{
+        fmt::{Display, Formatter},
+        io::SeekFrom,
+        future::Future,
+        pin::Pin,
+        process::{self, ExitStatus},
+        str::FromStr,
+        sync::{atomic::{AtomicUsize, Ordering::Relaxed}, Arc},
+        task::{Context, Poll},
+        time::Duration,
+    },
+    tokio::{
+        fs::{self, File},
+        io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+        process::Child,
+        time::{self, Instant, Sleep},
+    },
+    cache::{Cache, CacheLimits},
+    flight::Flights,
+    resolve::Resolver,
+    routes::Routes,
+    scheduler::{Job, JobLimits, Scheduler},
+    tokio_util::io::ReaderStream,
 };
 
-pub const CACHE_DIR: &str = env!("CACHE_DIR");
-pub const MAX_FILESIZE: usize = 1 << 30; // 1GB
+pub use {piped::Piped, yt_dlp::YtDlp};
 
-pub enum Input {
-    //Piped { id: String },
-    YtDlp { uri: String },
-}
+/// A source of media, e.g. `yt-dlp` or a Piped instance.
+pub trait Backend: Send + Sync {
+    /// Short name of the backend, used in logs.
+    fn name(&self) -> &'static str;
 
-impl Input {
-    pub fn from_uri(uri: &str) -> Option<Self> {
-        let uri = Uri::from_str(uri).ok()?;
-        let (path, query) = uri.path_and_query().map(|x| (x.path(), x.query()))?;
-        match uri.host()? {
-            "www.youtube.com" | "music.youtube.com" if path == "/watch" => query
-                .unwrap_or_default()
-                .split('&')
-                .find_map(|pair| pair.strip_prefix("v="))
-                .map(|id| Self::YtDlp { uri: format!("https://youtu.be/{id}") }),
-            "youtu.be" => path
-                .strip_prefix('/')
-                .map(|id| Self::YtDlp { uri: format!("https://youtu.be/{id}") }),
-            "www.instagram.com" => path
-                .strip_prefix("/reel/")
-                .map(|id| Self::YtDlp { uri: format!("https://www.instagram.com/reel/{id}") }),
-            host @(
-                | "vm.tiktok.com"
-                | "vk.com"
-                | "twitter.com"
-                | "x.com"
-            ) => Some(Self::YtDlp { uri: format!("https://{host}{path}") }),
-            _ => None,
-        }
+    /// Fetches the metadata of the media at `uri`, a canonical link produced by the routing
+    /// table, see `routes.toml`.
+    fn metadata<'a>(&'a self, uri: &'a str, options: Options)
+        -> BoxFuture<'a, Result<Metadata, Error>>;
+
+    /// Starts downloading the media described by `metadata`.
+    fn fetch<'a>(&'a self, uri: &'a str, options: Options, metadata: &'a Metadata)
+        -> BoxFuture<'a, Result<Media, Error>>;
+
+    /// Fetches the details about the media at `uri` to be shown before downloading it.
+    /// Unsupported by default, which makes the next backend be consulted instead.
+    fn info<'a>(&'a self, uri: &'a str) -> BoxFuture<'a, Result<Info, Error>> {
+        log::warn!("{} can't provide info about {uri:?}", self.name());
+        async { Err(Error::MetadataFetchFailed) }.boxed()
     }
 }
 
+/// Details about the media, shown to the user before downloading it.
+#[derive(Debug, Default, Serialize)]
+pub struct Info {
+    pub title: String,
+    pub uploader: Option<String>,
+    /// In seconds.
+    pub duration: Option<u64>,
+    pub thumbnail: Option<String>,
+    pub is_live: bool,
+    /// The number of entries if the link points to a playlist.
+    pub playlist_len: Option<usize>,
+    /// Sorted by quality, best first.
+    pub formats: Vec<FormatInfo>,
+}
+
+/// Format & quality the media can be downloaded in.
+#[derive(Debug, Serialize)]
+pub struct FormatInfo {
+    pub kind: MediaKind,
+    /// Accepted by [`Quality::from_str`], e.g. "720p" or "128k".
+    pub quality: String,
+    /// Estimated size of the download, in bytes.
+    pub filesize: Option<usize>,
+}
+
+/// Information about the media, obtained before downloading it.
+#[derive(Debug, Default)]
+pub struct Metadata {
+    pub title: String,
+    /// The number of entries if the link points to several media, e.g. a playlist, in which case
+    /// only `title` is meaningful besides it.
+    pub playlist_len: Option<usize>,
+    /// Size of the media in bytes, if known in advance.
+    pub filesize: Option<usize>,
+    pub duration: Option<Duration>,
+    pub is_live: bool,
+    /// Backend-specific identifier of the stream to be downloaded, e.g. a `yt-dlp` format ID.
+    pub stream_id: String,
+    /// The format the media is served in if the backend can't convert it to the requested one.
+    pub format: Option<OutputFormat>,
+}
+
+#[derive(Clone)]
+pub struct Input {
+    /// The canonical link.
+    uri: String,
+    /// All the backends allowed to download the media, in the order of priority.
+    backends: Vec<Arc<dyn Backend>>,
+    /// The kinds of media allowed to be downloaded from the link.
+    kinds: Vec<MediaKind>,
+    /// In bytes.
+    max_filesize: usize,
+    /// The moment the link points to, e.g. via `t=` in Youtube links.
+    start: Option<Duration>,
+    scheduler: Arc<Scheduler>,
+    flights: Arc<Flights>,
+    cache: Arc<Cache>,
+}
+
 impl Display for Input {
     fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
-        match self {
-            //Self::Piped { id } => write!(f, "https://youtu.be/{id}"),
-            Self::YtDlp { uri } => f.write_str(uri),
+        f.write_str(&self.uri)
+    }
+}
+
+impl Input {
+    /// Whether media of `kind` may be downloaded from the link.
+    pub fn allows(&self, kind: MediaKind) -> bool {
+        self.kinds.contains(&kind)
+    }
+
+    /// Fills in the options implied by the link itself, namely the start of the clip.
+    pub fn apply_defaults(&self, mut options: Options) -> Options {
+        if options.clip.start.is_none() {
+            options.clip = Clip::new(self.start, options.clip.end).unwrap_or(options.clip);
         }
+        options
     }
 }
 
+/// The variants that carry a message keep the one reported by the backend, for the logs.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Error {
     /// The requested audio/video couldn't be found.
     NotFound,
@@ -65,77 +168,789 @@ pub enum Error {
     DataFetchFailed,
     /// The provided link is either malformed or doesn't lead to a downloadable video/audio
     InvalidLink,
+    /// Too many downloads are running or waiting already.
+    Busy,
+    /// The media is private.
+    Private(Arc<str>),
+    /// The media requires the viewer to confirm their age.
+    AgeRestricted(Arc<str>),
+    /// The media isn't available in the server's country.
+    GeoBlocked(Arc<str>),
+    /// The media was removed by the uploader or the platform.
+    Removed(Arc<str>),
+    /// The media is only available to the channel's paying members.
+    MembersOnly(Arc<str>),
+    /// The media can only be viewed by logged-in users.
+    LoginRequired(Arc<str>),
+    /// The platform is refusing to serve the server's requests for the time being.
+    RateLimited(Arc<str>),
+    /// The link isn't recognised by the backend.
+    UnsupportedUrl(Arc<str>),
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+impl Error {
+    /// The message reported by the backend, if any.
+    pub fn message(&self) -> Option<&str> {
+        match self {
+            | Self::Private(msg)
+            | Self::AgeRestricted(msg)
+            | Self::GeoBlocked(msg)
+            | Self::Removed(msg)
+            | Self::MembersOnly(msg)
+            | Self::LoginRequired(msg)
+            | Self::RateLimited(msg)
+            | Self::UnsupportedUrl(msg) => Some(msg),
+            _ => None,
+        }
+    }
+
+    /// Explanation for the user of why the media is unavailable, for the errors caused by
+    /// restrictions of the platform rather than by the server.
+    pub const fn reason(&self) -> Option<&'static str> {
+        Some(match self {
+            Self::Private(_) => "The media is private",
+            Self::AgeRestricted(_) => "The media is age-restricted and can't be downloaded",
+            Self::GeoBlocked(_) => "The media isn't available in the server's country",
+            Self::Removed(_) => "The media has been removed",
+            Self::MembersOnly(_) => "The media is only available to the channel's members",
+            Self::LoginRequired(_) => "The media is only available to logged-in users",
+            Self::RateLimited(_) => "The platform is limiting requests, try again later",
+            Self::UnsupportedUrl(_) => "Links of this kind aren't supported",
+            _ => return None,
+        })
+    }
+
+    /// Whether the error is caused by the backend rather than the media itself, in which case
+    /// another backend may succeed.
+    const fn is_backend_failure(&self) -> bool {
+        matches!(self, Self::MetadataFetchFailed | Self::DataFetchFailed | Self::RateLimited(_))
+    }
+}
+
+/// Upper bound on the quality of the downloaded media.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Quality {
+    #[default]
+    Best,
+    /// Maximum height of the video in pixels, e.g. 720; ignored for audio.
+    Height(u16),
+    /// Maximum bitrate of the audio in kbps, e.g. 128.
+    Bitrate(u16),
+}
+
+/// Accepts "best", heights like "720p" or "720", and bitrates like "128k" or "128kbps".
+impl FromStr for Quality {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.to_ascii_lowercase();
+        if s == "best" {
+            return Ok(Self::Best);
+        }
+        if let Some(bitrate) = s.strip_suffix("kbps").or_else(|| s.strip_suffix('k')) {
+            return bitrate.parse().map(Self::Bitrate).map_err(drop);
+        }
+        s.strip_suffix('p').unwrap_or(&s).parse().map(Self::Height).map_err(drop)
+    }
+}
+
+impl Display for Quality {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            Self::Best => f.write_str("best"),
+            Self::Height(height) => write!(f, "{height}p"),
+            Self::Bitrate(bitrate) => write!(f, "{bitrate}k"),
+        }
+    }
+}
+
+impl Quality {
+    /// The argument for `yt-dlp -f` selecting the best format within the quality bounds, or the
+    /// worst one if none fit.
+    pub fn format_selector(self, mkind: MediaKind) -> String {
+        match (mkind, self) {
+            (MediaKind::Video, Self::Best) => "bv*+ba/b".to_owned(),
+            (MediaKind::Video, Self::Height(h)) => {
+                format!("bv*[height<={h}]+ba/b[height<={h}]/wv*+ba/w")
+            }
+            (MediaKind::Video, Self::Bitrate(k)) => {
+                format!("bv*+ba[abr<={k}]/b[abr<={k}]/bv*+wa/w")
+            }
+            (MediaKind::Audio, Self::Best | Self::Height(_)) => "ba/b".to_owned(),
+            (MediaKind::Audio, Self::Bitrate(k)) => format!("ba[abr<={k}]/wa/w"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum MediaKind {
     Video,
     Audio,
 }
 
 impl MediaKind {
-    pub const fn mime_type(self) -> &'static str {
+    /// The format used when none is requested explicitly.
+    pub const fn default_format(self) -> OutputFormat {
+        match self {
+            Self::Video => OutputFormat::Mp4,
+            Self::Audio => OutputFormat::Mp3,
+        }
+    }
+}
+
+/// The container/codec the downloaded media is converted to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Mp4,
+    Webm,
+    Mkv,
+    Mp3,
+    M4a,
+    Opus,
+    Flac,
+    Wav,
+}
+
+/// Accepts the extension of the format, e.g. "opus".
+impl FromStr for OutputFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL.into_iter().find(|f| f.extension().eq_ignore_ascii_case(s)).ok_or(())
+    }
+}
+
+impl OutputFormat {
+    pub const ALL: [Self; 8] = [
+        Self::Mp4, Self::Webm, Self::Mkv,
+        Self::Mp3, Self::M4a, Self::Opus, Self::Flac, Self::Wav,
+    ];
+
+    pub const fn kind(self) -> MediaKind {
         match self {
-            Self::Video => "video/mpeg",
-            Self::Audio => "audio/mpeg",
+            Self::Mp4 | Self::Webm | Self::Mkv => MediaKind::Video,
+            Self::Mp3 | Self::M4a | Self::Opus | Self::Flac | Self::Wav => MediaKind::Audio,
         }
     }
 
     pub const fn extension(self) -> &'static str {
         match self {
-            Self::Video => "mp4",
-            Self::Audio => "mp3",
+            Self::Mp4 => "mp4",
+            Self::Webm => "webm",
+            Self::Mkv => "mkv",
+            Self::Mp3 => "mp3",
+            Self::M4a => "m4a",
+            Self::Opus => "opus",
+            Self::Flac => "flac",
+            Self::Wav => "wav",
+        }
+    }
+
+    pub const fn mime_type(self) -> &'static str {
+        match self {
+            Self::Mp4 => "video/mp4",
+            Self::Webm => "video/webm",
+            Self::Mkv => "video/x-matroska",
+            Self::Mp3 => "audio/mpeg",
+            Self::M4a => "audio/mp4",
+            Self::Opus => "audio/ogg",
+            Self::Flac => "audio/flac",
+            Self::Wav => "audio/wav",
+        }
+    }
+
+    /// Arguments making `yt-dlp` convert the media to this format, using `ffmpeg` under the hood.
+    pub const fn yt_dlp_args(self) -> &'static [&'static str] {
+        match self {
+            Self::Mp4 => &["--recode-video", "mp4"],
+            Self::Webm => &["--recode-video", "webm"],
+            Self::Mkv => &["--remux-video", "mkv"],
+            Self::Mp3 => &["-x", "--audio-format", "mp3"],
+            Self::M4a => &["-x", "--audio-format", "m4a"],
+            Self::Opus => &["-x", "--audio-format", "opus"],
+            Self::Flac => &["-x", "--audio-format", "flac"],
+            Self::Wav => &["-x", "--audio-format", "wav"],
+        }
+    }
+}
+
+/// Parses timestamps like "1:20", "1:02:03", "80", "80s" or "1m20s".
+pub fn parse_timestamp(s: &str) -> Option<Duration> {
+    if s.is_empty() {
+        return None;
+    }
+    if s.contains(':') {
+        if s.split(':').count() > 3 {
+            return None;
+        }
+        return s.split(':')
+            .try_fold(0u64, |secs, part| secs.checked_mul(60)?.checked_add(part.parse().ok()?))
+            .map(Duration::from_secs);
+    }
+    if let Ok(secs) = s.parse() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let (mut secs, mut rest) = (0u64, s);
+    while !rest.is_empty() {
+        let unit_pos = rest.find(|c: char| !c.is_ascii_digit())?;
+        let n: u64 = rest[..unit_pos].parse().ok()?;
+        let unit = match rest.as_bytes()[unit_pos] {
+            b'h' => 3600,
+            b'm' => 60,
+            b's' => 1,
+            _ => return None,
+        };
+        secs = secs.checked_add(n.checked_mul(unit)?)?;
+        rest = &rest[unit_pos + 1 ..];
+    }
+    Some(Duration::from_secs(secs))
+}
+
+/// Displays the duration as "M:SS" or "H:MM:SS".
+pub struct Timestamp(pub Duration);
+
+impl Display for Timestamp {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        let secs = self.0.as_secs();
+        match (secs / 3600, secs / 60 % 60, secs % 60) {
+            (0, mins, secs) => write!(f, "{mins}:{secs:02}"),
+            (hours, mins, secs) => write!(f, "{hours}:{mins:02}:{secs:02}"),
         }
     }
 }
 
-pub enum Media {
-    YtDlp(yt_dlp::Media),
-    //Piped(piped::Media),
+/// A section of the media to be downloaded; the whole media by default.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Clip {
+    pub start: Option<Duration>,
+    pub end: Option<Duration>,
+}
+
+/// Accepts "START-END", where either of the timestamps may be omitted, e.g. "1:20-2:05" or "-30".
+impl FromStr for Clip {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s.split_once('-').ok_or(())?;
+        let parse = |t: &str| (!t.is_empty()).then(|| parse_timestamp(t).ok_or(())).transpose();
+        Self::new(parse(start)?, parse(end)?).ok_or(())
+    }
+}
+
+impl Display for Clip {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        if let Some(start) = self.start {
+            write!(f, "{}", Timestamp(start))?;
+        }
+        f.write_str("-")?;
+        if let Some(end) = self.end {
+            write!(f, "{}", Timestamp(end))?;
+        }
+        Ok(())
+    }
+}
+
+impl Clip {
+    /// Returns `None` if the clip would be empty.
+    pub fn new(start: Option<Duration>, end: Option<Duration>) -> Option<Self> {
+        match (start, end) {
+            (Some(start), Some(end)) if end <= start => None,
+            _ => Some(Self { start, end }),
+        }
+    }
+
+    pub const fn is_full(self) -> bool {
+        self.start.is_none() && self.end.is_none()
+    }
+
+    /// Estimates the size of the clip from the size & duration of the whole media.
+    fn estimate_size(self, filesize: usize, duration: Duration) -> usize {
+        let start = self.start.unwrap_or_default().min(duration);
+        let end = self.end.unwrap_or(duration).min(duration);
+        let share = end.saturating_sub(start).as_millis();
+        let estimate = filesize as u128 * share / duration.as_millis().max(1);
+        estimate.try_into().unwrap_or(usize::MAX)
+    }
+}
+
+/// How the media is to be downloaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Options {
+    pub format: OutputFormat,
+    pub quality: Quality,
+    pub clip: Clip,
+    /// 1-based index of the entry to download if the link points to a playlist.
+    pub item: Option<usize>,
+}
+
+/// Used in logs; e.g. "mp3 128k 1:20-2:05".
+impl Display for Options {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "{} {}", self.format.extension(), self.quality)?;
+        if !self.clip.is_full() {
+            write!(f, " {}", self.clip)?;
+        }
+        Ok(())
+    }
+}
+
+impl Options {
+    pub const fn new(mkind: MediaKind) -> Self {
+        Self {
+            format: mkind.default_format(),
+            quality: Quality::Best,
+            clip: Clip { start: None, end: None },
+            item: None,
+        }
+    }
+
+    /// Applies an option given as a bot command argument, e.g. "720p", "opus" or "1:20-2:05".
+    /// Returns `false` if `arg` isn't a valid option.
+    pub fn parse_arg(&mut self, arg: &str) -> bool {
+        if let Ok(quality) = Quality::from_str(arg) {
+            self.quality = quality;
+        } else if let Some(format) = OutputFormat::from_str(arg).ok()
+            .filter(|f| f.kind() == self.format.kind())
+        {
+            self.format = format;
+        } else if let Ok(clip) = Clip::from_str(arg) {
+            self.clip = clip;
+        } else {
+            return false;
+        }
+        true
+    }
+}
+
+pub struct Media {
+    inner: BoxStream<'static, Result<Bytes>>,
+    filesize: usize,
+    filename: String,
+    /// Resolves once the process producing the media exits; dropping it kills the process.
+    process: Option<BoxFuture<'static, std::io::Result<ExitStatus>>>,
+    /// The download is aborted once this elapses.
+    deadline: Option<Pin<Box<Sleep>>>,
+    /// Released once the media is dropped, letting other downloads run.
+    job: Option<Job>,
 }
 
 impl Stream for Media {
     type Item = Result<Bytes>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
-        match &mut *self {
-            Self::YtDlp(media) => media.poll_next_unpin(cx),
-            //Self::Piped(media) => media.poll_next_unpin(cx),
+        if self.deadline.as_mut().is_some_and(|deadline| deadline.as_mut().poll(cx).is_ready()) {
+            self.inner = stream::empty().boxed();
+            self.process = None;
+            self.deadline = None;
+            return Poll::Ready(Some(Err("the download timed out".into())));
+        }
+        if let Some(chunk) = ready!(self.inner.poll_next_unpin(cx)) {
+            return Poll::Ready(Some(chunk));
         }
+        let Some(process) = &mut self.process else { return Poll::Ready(None) };
+        let status = ready!(process.poll_unpin(cx));
+        self.process = None;
+        self.deadline = None;
+        Poll::Ready(match status {
+            Ok(status) if status.success() => None,
+            Ok(status) => Some(Err(format!("the process exited unsuccessfully: {status}").into())),
+            Err(err) => Some(Err(err.into())),
+        })
     }
 
     fn size_hint(&self) -> (usize, Option<usize>) {
-        match self {
-            Self::YtDlp(media) => media.size_hint(),
-            //Self::Piped(media) => media.size_hint(),
-        }
+        (self.filesize, Some(self.filesize))
     }
 }
 
 impl Media {
-    pub async fn get(input: Input, mkind: MediaKind) -> Result<Self, Error> {
-        match input {
-            Input::YtDlp { uri } => yt_dlp::Media::get(uri, mkind).await.map(Self::YtDlp),
-            //Input::Piped { id } => piped::Media::get(id, mkind).await.map(Self::Piped),
+    /// For use by backends; the filename is assigned by [`Download::get`].
+    pub fn new(inner: impl Stream<Item = Result<Bytes>> + Send + 'static, filesize: usize) -> Self {
+        Self {
+            inner: inner.boxed(),
+            filesize,
+            filename: String::new(),
+            process: None,
+            deadline: None,
+            job: None,
         }
     }
 
-    pub fn filename(&self) -> &str {
-        match self {
-            Self::YtDlp(media) => &media.filename,
-            //Self::Piped(media) => &media.filename,
+    /// Makes the media own the process producing it: the process is killed if the media is dropped
+    /// or isn't fully read by `deadline`, and its unsuccessful exit is reported as an error at the
+    /// end of the stream. `child` is expected to be spawned with [`Command::kill_on_drop`] set.
+    ///
+    /// [`Command::kill_on_drop`]: tokio::process::Command::kill_on_drop
+    #[must_use]
+    pub fn with_process(mut self, mut child: Child, deadline: Instant) -> Self {
+        self.process = Some(async move { child.wait().await }.boxed());
+        self.deadline = Some(Box::pin(time::sleep_until(deadline)));
+        self
+    }
+
+    /// For use by backends when the size of the media isn't known in advance: the media is written
+    /// to a temporary file in `dir`, aborting once it exceeds `max_filesize`, and then streamed
+    /// from the file.
+    pub async fn spill(mut reader: impl AsyncRead + Unpin + Send, dir: &str, max_filesize: usize)
+        -> Result<Self, Error>
+    {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+        let path = format!("{dir}{}-{}.part", process::id(), NEXT_ID.fetch_add(1, Relaxed));
+        let res: Result<_, std::io::Error> = async {
+            let mut file = File::options()
+                .read(true).write(true).create_new(true)
+                .open(&path).await?;
+            // The file remains accessible through the open handle until it's dropped, and nothing
+            // is left behind if this future is dropped midway, e.g. on a timeout.
+            if let Err(err) = fs::remove_file(&path).await {
+                log::error!("Failed to remove {path:?}: {err}");
+            }
+            let (mut buf, mut filesize) = (vec![0; 1 << 16], 0);
+            loop {
+                let n = reader.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                filesize += n;
+                if filesize > max_filesize {
+                    return Ok(None);
+                }
+                file.write_all(&buf[..n]).await?;
+            }
+            file.flush().await?;
+            file.seek(SeekFrom::Start(0)).await?;
+            Ok(Some((file, filesize)))
+        }.await;
+
+        match res {
+            Ok(Some((file, filesize))) => {
+                Ok(Self::new(ReaderStream::new(file).map_err(Into::into), filesize))
+            }
+            Ok(None) => Err(Error::TooLarge),
+            Err(err) => {
+                log::error!("Failed to write the media to {path:?}: {err}");
+                Err(Error::DataFetchFailed)
+            }
         }
     }
 
+    /// Downloads a single entry of a playlist or the only media at the link.
+    pub async fn get(input: Input, options: Options) -> Result<Self, Error> {
+        let Download::Media(media) = Download::get(input, options, PlaylistLimits::default())
+            .await?
+        else {
+            return Err(Error::InvalidLink);
+        };
+        Ok(media)
+    }
+
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+
     pub fn filename_mut(&mut self) -> &mut String {
-        match self {
-            Self::YtDlp(media) => &mut media.filename,
-            //Self::Piped(media) => &mut media.filename,
+        &mut self.filename
+    }
+
+    /// The format of the media according to the extension of its filename, which may differ from
+    /// the requested one, see [`Metadata::format`].
+    pub fn format(&self) -> Option<OutputFormat> {
+        self.filename.rsplit_once('.')?.1.parse().ok()
+    }
+}
+
+/// Caps on what's downloaded from a single playlist.
+#[derive(Debug, Clone, Copy)]
+pub struct PlaylistLimits {
+    /// Entries past this number are ignored.
+    pub max_entries: usize,
+    /// Downloading stops once the entries downloaded so far exceed this size in total.
+    pub max_size: usize,
+}
+
+impl Default for PlaylistLimits {
+    fn default() -> Self {
+        Self { max_entries: 50, max_size: 2 << 30 }
+    }
+}
+
+/// Several media behind a single link, e.g. a playlist, downloaded one by one.
+pub struct Playlist {
+    pub title: String,
+    input: Input,
+    options: Options,
+    limits: PlaylistLimits,
+    /// The number of entries to be downloaded, after applying the limits.
+    len: usize,
+    /// The number of entries in the playlist, before applying the limits.
+    total_len: usize,
+    /// 1-based index of the next entry to be downloaded.
+    next: usize,
+    /// Total size of the entries downloaded so far.
+    size: usize,
+}
+
+impl Playlist {
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether some of the entries will be skipped due to [`PlaylistLimits::max_entries`].
+    pub const fn is_truncated(&self) -> bool {
+        self.len < self.total_len
+    }
+
+    /// Whether the rest of the entries were skipped due to [`PlaylistLimits::max_size`].
+    pub const fn exceeds_max_size(&self) -> bool {
+        self.size > self.limits.max_size
+    }
+
+    /// Returns `None` once all the entries are downloaded or once the total size limit is reached,
+    /// see [`Self::exceeds_max_size`]; the entries that failed to download, including the ones too
+    /// large by themselves, don't stop the process.
+    pub async fn next(&mut self) -> Option<Result<Media, Error>> {
+        if self.next > self.len || self.exceeds_max_size() {
+            return None;
+        }
+        let options = Options { item: Some(self.next), ..self.options };
+        self.next += 1;
+
+        let res = Media::get(self.input.clone(), options).await;
+        if let Ok(media) = &res {
+            self.size = self.size.saturating_add(media.size_hint().0);
+            if self.exceeds_max_size() {
+                return None;
+            }
+        }
+        Some(res)
+    }
+}
+
+pub enum Download {
+    Media(Media),
+    Playlist(Playlist),
+}
+
+impl Download {
+    /// The media is taken from the cache if possible; if the same media has just started being
+    /// downloaded with the same options, the bytes are taken from that download instead of
+    /// starting a new one.
+    pub async fn get(input: Input, options: Options, limits: PlaylistLimits)
+        -> Result<Self, Error>
+    {
+        let options = input.apply_defaults(options);
+        let kind = options.format.kind();
+        if !input.kinds.contains(&kind) {
+            return Err(Error::UnsupportedUrl(format!("{kind:?} is disabled for {input}").into()));
+        }
+        let key = format!("{input} {options:?}");
+        if let Some(media) = input.cache.get(&key).await {
+            log::info!("Serving {key:?} from the cache");
+            return Ok(Self::Media(media));
+        }
+
+        let cache = input.cache.clone();
+        input.flights.clone().run(key.clone(), async move {
+            match Self::get_unshared(input, options, limits).await {
+                Ok(Self::Media(media)) => Ok(Self::Media(cache.tee(key, media))),
+                res => res,
+            }
+        }).await
+    }
+
+    /// Tries the backends of `input` in order, moving on to the next one only if a backend failed
+    /// to fetch the media for reasons unrelated to the media itself.
+    async fn get_unshared(input: Input, options: Options, limits: PlaylistLimits)
+        -> Result<Self, Error>
+    {
+        let mut res = Err(Error::InvalidLink);
+        for backend in &input.backends {
+            let job = input.scheduler.start(backend.name()).await?;
+            res = Self::get_with(&input, &**backend, options).await;
+            if let Ok(Self::Media(media)) = &mut res {
+                media.job = Some(job);
+            }
+            match &res {
+                Err(err) if err.is_backend_failure() => log::warn!(
+                    "{} failed to download {:?}, falling back to the next backend",
+                    backend.name(),
+                    input.uri,
+                ),
+                _ => break,
+            }
+        }
+
+        if let Ok(Self::Playlist(playlist)) = &mut res {
+            playlist.limits = limits;
+            if playlist.len > limits.max_entries {
+                log::info!("Only the first {} of {} entries of {:?} will be downloaded",
+                           limits.max_entries, playlist.len, playlist.input.uri);
+                playlist.len = limits.max_entries;
+            }
+        }
+        res
+    }
+
+    /// The `limits` of the returned playlist are to be filled in by the caller.
+    async fn get_with(input: &Input, backend: &dyn Backend, options: Options)
+        -> Result<Self, Error>
+    {
+        let uri = &*input.uri;
+        log::info!("Downloading {uri:?} as {options} using {}", backend.name());
+        let mut metadata = backend.metadata(uri, options).await?;
+        if let Some(len) = metadata.playlist_len {
+            return Ok(Self::Playlist(Playlist {
+                title: metadata.title,
+                input: input.clone(),
+                options,
+                limits: PlaylistLimits::default(),
+                len,
+                total_len: len,
+                next: 1,
+                size: 0,
+            }));
+        }
+        if metadata.is_live {
+            return Err(Error::IsStream);
+        }
+        if !options.clip.is_full() {
+            // The exact size of a clip can't be known in advance, only estimated.
+            let estimate = metadata.filesize.take()
+                .zip(metadata.duration)
+                .map(|(size, duration)| options.clip.estimate_size(size, duration));
+            if estimate.is_some_and(|size| size >= input.max_filesize) {
+                return Err(Error::TooLarge);
+            }
         }
+        if metadata.filesize.is_some_and(|size| size >= input.max_filesize) {
+            return Err(Error::TooLarge);
+        }
+
+        let mut media = backend.fetch(uri, options, &metadata).await?;
+        let format = metadata.format.unwrap_or(options.format);
+        media.filename = format!("{}.{}", metadata.title, format.extension());
+        Ok(Self::Media(media))
     }
 }
 
-pub async fn download(uri: &str, mkind: MediaKind) -> Result<Media, Error> {
-    let input = Input::from_uri(uri).ok_or(Error::InvalidLink)?;
-    Media::get(input, mkind).await
+/// Registry of the backends, consulted in the order of their priority.
+pub struct Downloader {
+    /// Sorted by priority, highest first.
+    backends: Vec<(u8, Arc<dyn Backend>)>,
+    pub playlist_limits: PlaylistLimits,
+    scheduler: Arc<Scheduler>,
+    flights: Arc<Flights>,
+    cache: Arc<Cache>,
+    resolver: Resolver,
+    routes: Routes,
+    /// In bytes.
+    max_filesize: usize,
+}
+
+impl Downloader {
+    /// Registers the default backends, loads the media cache & the routing table, which may only
+    /// refer to the registered backends.
+    pub fn new(config: &Config) -> Result<Self> {
+        let download = &config.download;
+        let mut res = Self {
+            backends: vec![],
+            playlist_limits: PlaylistLimits {
+                max_entries: download.max_playlist_entries,
+                max_size: download.max_playlist_size(),
+            },
+            scheduler: Arc::new(Scheduler::new(&JobLimits {
+                max_jobs: download.max_jobs,
+                max_queued: download.max_queued,
+                ..JobLimits::default()
+            })),
+            flights: Arc::default(),
+            cache: Arc::new(Cache::new(&config.cache_dir, CacheLimits {
+                max_size: download.cache_max_size,
+                ttl: download.cache_ttl(),
+            })?),
+            resolver: Resolver::default(),
+            routes: Routes::default(),
+            max_filesize: download.max_filesize,
+        };
+        res.register(u8::MAX / 2, YtDlp {
+            timeout: download.yt_dlp_timeout(),
+            tmp_dir: config.cache_dir.clone(),
+            max_filesize: download.max_filesize,
+        });
+        res.register(u8::MAX / 4, Piped::new(&download.piped_instances));
+        let names: Vec<_> = res.backends.iter().map(|(_, backend)| backend.name()).collect();
+        res.routes = Routes::load(&names)?;
+        Ok(res)
+    }
+
+    /// Backends with higher `priority` are consulted first; among backends of equal priority,
+    /// the one registered earlier wins.
+    pub fn register(&mut self, priority: u8, backend: impl Backend + 'static) {
+        let index = self.backends.partition_point(|&(p, _)| p >= priority);
+        self.backends.insert(index, (priority, Arc::new(backend)));
+    }
+
+    /// Finds the backends allowed to download from `link` by the routing table, after following
+    /// it if it's a shortened link.
+    pub async fn input(&self, link: &str) -> Option<Input> {
+        let uri = self.resolver.resolve(&Uri::from_str(link).ok()?).await?;
+        let (rule, canonical) = self.routes.route(&uri)?;
+        if !rule.enabled {
+            log::info!("Rejecting {uri}: rule {:?} is disabled", rule.name);
+            return None;
+        }
+        let backends: Vec<_> = if rule.backends.is_empty() {
+            self.backends.iter().map(|(_, backend)| backend.clone()).collect()
+        } else {
+            rule.backends
+                .iter()
+                .filter_map(|name| self.backends.iter().find(|(_, x)| x.name() == name))
+                .map(|(_, backend)| backend.clone())
+                .collect()
+        };
+        if backends.is_empty() {
+            return None;
+        }
+        let start = uri.query()
+            .unwrap_or_default()
+            .split('&')
+            .find_map(|pair| parse_timestamp(pair.strip_prefix("t=")?));
+        Some(Input {
+            uri: canonical,
+            backends,
+            kinds: rule.kinds.clone(),
+            max_filesize: self.max_filesize,
+            start,
+            scheduler: self.scheduler.clone(),
+            flights: self.flights.clone(),
+            cache: self.cache.clone(),
+        })
+    }
+
+    pub async fn get(&self, input: Input, options: Options) -> Result<Download, Error> {
+        Download::get(input, options, self.playlist_limits).await
+    }
+
+    /// Tries the backends capable of downloading from `link` in order, like [`Download::get`].
+    pub async fn info(&self, link: &str) -> Result<Info, Error> {
+        let input = self.input(link).await.ok_or(Error::InvalidLink)?;
+        let mut res = Err(Error::InvalidLink);
+        for backend in &input.backends {
+            let _job = input.scheduler.start(backend.name()).await?;
+            res = backend.info(&input.uri).await;
+            if !res.as_ref().is_err_and(Error::is_backend_failure) {
+                break;
+            }
+        }
+        let mut info = res?;
+        info.formats.retain(|format| input.kinds.contains(&format.kind));
+        Ok(info)
+    }
+
+    pub async fn download(&self, link: &str, options: Options) -> Result<Download, Error> {
+        let input = self.input(link).await.ok_or(Error::InvalidLink)?;
+        self.get(input, options).await
+    }
 }
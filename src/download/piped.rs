@@ -1,17 +1,31 @@
 use {
-    super::{Error, MediaKind},
-    crate::utils::Result,
-    axum::body::Bytes,
-    futures::{Stream, StreamExt, TryFutureExt},
+    super::{Backend, Error, Media, MediaKind, Metadata, Options, OutputFormat, Quality},
+    futures::{future::BoxFuture, FutureExt, TryStreamExt},
     serde::Deserialize,
-    std::{pin::Pin, task::{Context, Poll}},
+    std::{
+        sync::{atomic::{AtomicU32, AtomicUsize, Ordering::Relaxed}, Mutex},
+        time::{Duration, Instant},
+    },
 };
 
+/// How long an instance is skipped for after its first failure; doubles with each consecutive one.
+const BASE_COOLDOWN: Duration = Duration::from_secs(30);
+const MAX_COOLDOWN: Duration = Duration::from_mins(30);
+/// Timeout for requesting the metadata; the media itself is streamed without one.
+const METADATA_TIMEOUT: Duration = Duration::from_secs(15);
+
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct MediaStream {
     bitrate: u32,
-    content_length: usize,
+    #[serde(default)]
+    height: u32,
+    #[serde(default)]
+    content_length: i64,
+    #[serde(default)]
+    mime_type: String,
+    #[serde(default)]
+    video_only: bool,
     url: String,
 }
 
@@ -19,70 +33,267 @@ struct MediaStream {
 #[serde(rename_all = "camelCase")]
 struct MediaData {
     title: String,
+    #[serde(default)]
+    duration: u64,
+    #[serde(default)]
+    livestream: bool,
     audio_streams: Vec<MediaStream>,
     video_streams: Vec<MediaStream>,
 }
 
-type BytesStream = impl Stream<Item = reqwest::Result<Bytes>> + Unpin;
-
-pub struct Media {
-    inner: BytesStream,
-    content_length: usize,
-    pub filename: String,
+struct Instance {
+    url: Box<str>,
+    /// Number of consecutive failures.
+    failures: AtomicU32,
+    /// The instance is not tried before this moment unless all the other instances are down too.
+    cooldown_until: Mutex<Option<Instant>>,
 }
 
-impl Stream for Media {
-    type Item = Result<Bytes>;
+impl Instance {
+    fn is_healthy(&self) -> bool {
+        self.cooldown_until.lock()
+            .map_or(true, |until| until.is_none_or(|until| until <= Instant::now()))
+    }
 
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        self.inner.poll_next_unpin(cx).map_err(Into::into)
+    fn record_success(&self) {
+        self.failures.store(0, Relaxed);
+        if let Ok(mut until) = self.cooldown_until.lock() {
+            *until = None;
+        }
     }
 
-    fn size_hint(&self) -> (usize, Option<usize>) {
-        (self.content_length, Some(self.content_length))
+    fn record_failure(&self) {
+        let failures = self.failures.fetch_add(1, Relaxed).saturating_add(1);
+        let cooldown = BASE_COOLDOWN
+            .saturating_mul(1 << failures.saturating_sub(1).min(16))
+            .min(MAX_COOLDOWN);
+        log::warn!("Piped instance {} is down, skipping it for {cooldown:?}", self.url);
+        if let Ok(mut until) = self.cooldown_until.lock() {
+            *until = Some(Instant::now() + cooldown);
+        }
     }
 }
 
-impl Media {
-    const X: usize = std::mem::size_of::<BytesStream>();
+/// Downloads Youtube videos via the API of a [Piped](https://github.com/TeamPiped/Piped)
+/// instance, rotating through the instances and skipping the ones that failed recently.
+pub struct Piped {
+    instances: Box<[Instance]>,
+    /// Index of the instance to try first on the next request.
+    next: AtomicUsize,
+    client: reqwest::Client,
+}
+
+impl Piped {
+    /// `instances` are base URLs of the Piped API, e.g. `https://pipedapi.kavin.rocks`
+    pub fn new(instances: impl IntoIterator<Item = impl AsRef<str>>) -> Self {
+        let instances = instances
+            .into_iter()
+            .map(|url| url.as_ref().trim().trim_end_matches('/').to_owned())
+            .filter(|url| !url.is_empty())
+            .map(|url| Instance {
+                url: url.into(),
+                failures: AtomicU32::new(0),
+                cooldown_until: Mutex::new(None),
+            })
+            .collect();
+        Self { instances, next: AtomicUsize::new(0), client: reqwest::Client::new() }
+    }
 
-    pub async fn get(mut id: String, mkind: MediaKind) -> Result<Self, Error> {
-        id.insert_str(0, "https://pipedapi.r4fo.com/streams/");
-        let MediaData { mut title, audio_streams, video_streams } = reqwest::get(id)
-            .and_then(reqwest::Response::json)
-            .await
-            .map_err(|e| {
-                log::error!("Failed to extract metadata from Piped API: {e}");
+    /// Healthy instances come first, in rotation order; the ones on cooldown are tried last.
+    fn instances(&self) -> impl Iterator<Item = &Instance> {
+        let start = self.next.fetch_add(1, Relaxed);
+        let rotated = || (0..self.instances.len())
+            .map(move |i| &self.instances[(start + i) % self.instances.len()]);
+        rotated().filter(|i| i.is_healthy()).chain(rotated().filter(|i| !i.is_healthy()))
+    }
+
+    /// Only the formats that Piped provides as-is are supported, and only the whole media can be
+    /// downloaded, since the media isn't processed in any way. MP3 is served as M4A instead, so
+    /// that audio requests in the default format can still fall back to Piped.
+    async fn fetch_metadata(&self, uri: &str, options: Options) -> Result<Metadata, Error> {
+        let Options { format, quality, clip, item } = options;
+        let id = uri.strip_prefix("https://youtu.be/").ok_or(Error::InvalidLink)?;
+        let mkind = format.kind();
+        if !clip.is_full() {
+            log::warn!("Piped can't download clips");
+            return Err(Error::MetadataFetchFailed);
+        }
+        if item.is_some_and(|item| item != 1) {
+            log::warn!("Piped can't download playlists");
+            return Err(Error::MetadataFetchFailed);
+        }
+        let served = match format {
+            OutputFormat::Mp3 => OutputFormat::M4a,
+            OutputFormat::Mp4 | OutputFormat::Webm | OutputFormat::M4a => format,
+            _ => {
+                log::warn!("Piped doesn't support {format:?}");
+                return Err(Error::MetadataFetchFailed);
+            }
+        };
+        let mime_type = served.mime_type();
+        let mut data = None;
+        for instance in self.instances() {
+            let res = self.client
+                .get(format!("{}/streams/{id}", instance.url))
+                .timeout(METADATA_TIMEOUT)
+                .send().await
+                .and_then(reqwest::Response::error_for_status);
+            match res {
+                Ok(res) => match res.json::<MediaData>().await {
+                    Ok(res) => {
+                        instance.record_success();
+                        data = Some(res);
+                        break;
+                    }
+                    Err(e) => {
+                        log::error!("Failed to decode metadata from {}: {e}", instance.url);
+                        instance.record_failure();
+                    }
+                }
+                Err(e) if e.status() == Some(reqwest::StatusCode::NOT_FOUND) => {
+                    instance.record_success();
+                    return Err(Error::NotFound);
+                }
+                Err(e) => {
+                    log::error!("Failed to extract metadata from {}: {e}", instance.url);
+                    instance.record_failure();
+                }
+            }
+        }
+        let MediaData { title, duration, livestream, audio_streams, video_streams } = data
+            .ok_or(Error::MetadataFetchFailed)?;
+
+        let streams = match mkind {
+            MediaKind::Video => video_streams,
+            MediaKind::Audio => audio_streams,
+        };
+        let fits = |s: &&MediaStream| match (mkind, quality) {
+            (MediaKind::Video, Quality::Height(height)) => s.height <= height.into(),
+            (MediaKind::Audio, Quality::Bitrate(bitrate)) => s.bitrate <= u32::from(bitrate) * 1000,
+            _ => true,
+        };
+        let streams: Vec<_> = streams
+            .into_iter()
+            .filter(|s| !s.video_only && s.mime_type == mime_type && s.content_length > 0)
+            .collect();
+        let stream = streams.iter()
+            .filter(fits)
+            .max_by_key(|s| s.bitrate)
+            .or_else(|| streams.iter().min_by_key(|s| s.bitrate))
+            .ok_or_else(|| {
+                log::error!("No suitable {format:?} streams found for {uri}");
                 Error::MetadataFetchFailed
             })?;
 
-        let stream = match mkind {
-            MediaKind::Video => video_streams
-                .iter()
-                .max_by_key(|&stream| stream.bitrate)
-                .ok_or_else(|| {
-                    log::error!("No video streams found");
-                    Error::MetadataFetchFailed
-                })?,
-            MediaKind::Audio => audio_streams
-                .iter()
-                .max_by_key(|&stream| stream.bitrate)
-                .ok_or_else(|| {
-                    log::error!("No audio streams found");
-                    Error::MetadataFetchFailed
-                })?,
-        };
+        Ok(Metadata {
+            title,
+            filesize: stream.content_length.try_into().ok(),
+            duration: (duration > 0).then(|| Duration::from_secs(duration)),
+            is_live: livestream,
+            stream_id: stream.url.clone(),
+            playlist_len: None,
+            format: (served != format).then_some(served),
+        })
+    }
 
-        let inner: BytesStream = reqwest::get(&stream.url)
-            .map_ok(reqwest::Response::bytes_stream)
-            .await
+    async fn fetch_media(&self, metadata: &Metadata) -> Result<Media, Error> {
+        let res = self.client.get(&metadata.stream_id)
+            .send().await
+            .and_then(reqwest::Response::error_for_status)
             .map_err(|e| {
-                log::error!("Failed to download the video: {e}");
+                log::error!("Failed to download the media from Piped: {e}");
                 Error::DataFetchFailed
             })?;
+        let filesize = metadata.filesize.or(res.content_length().and_then(|l| l.try_into().ok()))
+            .ok_or(Error::DataFetchFailed)?;
+        Ok(Media::new(res.bytes_stream().map_err(Into::into), filesize))
+    }
+}
+
+impl Backend for Piped {
+    fn name(&self) -> &'static str {
+        "Piped"
+    }
+
+    fn metadata<'a>(&'a self, uri: &'a str, options: Options)
+        -> BoxFuture<'a, Result<Metadata, Error>>
+    {
+        self.fetch_metadata(uri, options).boxed()
+    }
+
+    fn fetch<'a>(&'a self, _: &'a str, _: Options, metadata: &'a Metadata)
+        -> BoxFuture<'a, Result<Media, Error>>
+    {
+        self.fetch_media(metadata).boxed()
+    }
+}
+
+#[cfg(test)]
+#[expect(clippy::unwrap_used, reason = "panicking is how tests fail")]
+mod tests {
+    use {
+        super::*,
+        axum::{extract::Path, http::StatusCode, routing::get, Json, Router},
+        serde_json::json,
+        tokio::net::TcpListener,
+    };
+
+    const MEDIA: &[u8] = b"not really an m4a file";
+
+    /// Starts a stand-in for a Piped instance that only knows the video "abc", returning its URL.
+    async fn serve_instance() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let streams = json!({
+            "title": "Title",
+            "duration": 60,
+            "livestream": false,
+            "audioStreams": [
+                {
+                    "bitrate": 128_000,
+                    "contentLength": MEDIA.len(),
+                    "mimeType": "audio/mp4",
+                    "url": format!("{url}/media"),
+                },
+                {
+                    "bitrate": 160_000,
+                    "contentLength": 1,
+                    "mimeType": "audio/webm",
+                    "url": format!("{url}/webm"),
+                },
+            ],
+            "videoStreams": [],
+        });
+        let router = Router::new()
+            .route("/streams/:id", get(|Path(id): Path<String>| async move {
+                if id == "abc" { Ok(Json(streams)) } else { Err(StatusCode::NOT_FOUND) }
+            }))
+            .route("/media", get(|| async { MEDIA }));
+        tokio::spawn(async { axum::serve(listener, router).await });
+        url
+    }
+
+    #[tokio::test]
+    async fn serves_mp3_as_m4a() {
+        // The first instance is down, so the second one is expected to be used instead.
+        let piped = Piped::new(["http://127.0.0.1:1".to_owned(), serve_instance().await]);
+        let options = Options::new(MediaKind::Audio);
+        let metadata = piped.metadata("https://youtu.be/abc", options).await.unwrap();
+        assert_eq!(metadata.title, "Title");
+        assert_eq!(metadata.format, Some(OutputFormat::M4a));
+        assert_eq!(metadata.filesize, Some(MEDIA.len()));
+
+        let media = piped.fetch("https://youtu.be/abc", options, &metadata).await.unwrap();
+        let chunks: Vec<_> = media.try_collect().await.unwrap();
+        assert_eq!(chunks.concat(), MEDIA);
+        assert!(!piped.instances[0].is_healthy());
+    }
 
-        title.push('.');
-        title.push_str(mkind.extension());
-        Ok(Self { inner, content_length: stream.content_length, filename: title })
+    #[tokio::test]
+    async fn reports_missing_videos() {
+        let piped = Piped::new([serve_instance().await]);
+        let res = piped.metadata("https://youtu.be/xyz", Options::new(MediaKind::Audio)).await;
+        assert_eq!(res.unwrap_err(), Error::NotFound);
+        assert!(piped.instances[0].is_healthy());
     }
 }
@@ -0,0 +1,83 @@
+use {
+    axum::http::Uri,
+    reqwest::{header::LOCATION, redirect::Policy, Url},
+    std::{str::FromStr, time::Duration},
+};
+
+/// Hosts of link shorteners, whose links are followed to find out where they lead.
+/// Only these are followed to avoid making requests to arbitrary hosts on the user's behalf.
+const SHORTENERS: &[&str] = &[
+    "t.co",
+    "bit.ly",
+    "tinyurl.com",
+    "is.gd",
+    "ow.ly",
+    "buff.ly",
+    "vk.cc",
+    "vt.tiktok.com",
+    "pin.it",
+];
+const MAX_HOPS: usize = 5;
+/// Timeout for each of the requests made while following a link.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Maps mobile & alternate domains onto the ones the backends expect, and Youtube Shorts onto
+/// regular Youtube links.
+pub fn canonicalize(uri: &Uri) -> Option<Uri> {
+    let host = match uri.host()? {
+        "youtube.com" | "m.youtube.com" => "www.youtube.com",
+        "www.twitter.com" | "mobile.twitter.com" | "vxtwitter.com" | "fxtwitter.com" => {
+            "twitter.com"
+        }
+        "www.x.com" | "mobile.x.com" | "fixupx.com" => "x.com",
+        "m.vk.com" => "vk.com",
+        "instagram.com" | "instagr.am" | "www.instagr.am" => "www.instagram.com",
+        "m.soundcloud.com" => "soundcloud.com",
+        "tiktok.com" | "m.tiktok.com" => "www.tiktok.com",
+        host => host,
+    };
+    let path_and_query = match uri.path().strip_prefix("/shorts/") {
+        Some(id) if host == "www.youtube.com" => format!("/watch?v={id}"),
+        _ => uri.path_and_query().map_or("/", |x| x.as_str()).to_owned(),
+    };
+    Uri::from_str(&format!("https://{host}{path_and_query}")).ok()
+}
+
+/// Follows the redirects of link shorteners.
+pub struct Resolver {
+    client: reqwest::Client,
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        let client = reqwest::Client::builder()
+            .redirect(Policy::none())
+            .timeout(TIMEOUT)
+            .build()
+            .unwrap_or_default();
+        Self { client }
+    }
+}
+
+impl Resolver {
+    /// Returns the canonical form of the link that `uri` leads to; if `uri` is a shortened link,
+    /// its redirects are followed.
+    pub async fn resolve(&self, uri: &Uri) -> Option<Uri> {
+        let mut uri = canonicalize(uri)?;
+        for _ in 0..MAX_HOPS {
+            if !uri.host().is_some_and(|host| SHORTENERS.contains(&host)) {
+                return Some(uri);
+            }
+            let url = Url::parse(&uri.to_string()).ok()?;
+            let res = self.client.head(url.clone()).send().await
+                .inspect_err(|err| log::warn!("Failed to resolve {uri}: {err}"))
+                .ok()?;
+            let location = res.headers().get(LOCATION)?.to_str().ok()?;
+            let next = url.join(location).ok()?;
+            log::info!("{uri} redirects to {next}");
+            uri = canonicalize(&Uri::from_str(next.as_str()).ok()?)?;
+        }
+        log::warn!("Too many redirects while resolving {uri}");
+        None
+    }
+}
@@ -0,0 +1,159 @@
+//! The table of the links accepted by the downloader, see `routes.toml` for the format.
+
+use {
+    super::MediaKind,
+    crate::utils::Result,
+    axum::http::Uri,
+    serde::Deserialize,
+    std::io::ErrorKind::NotFound,
+};
+
+const PATH: &str = "routes.toml";
+/// Used if there's no `routes.toml` in the working directory.
+const DEFAULT: &str = include_str!("../../routes.toml");
+
+fn any_path() -> String {
+    "/{*path}".to_owned()
+}
+
+const fn yes() -> bool {
+    true
+}
+
+fn all_kinds() -> Vec<MediaKind> {
+    vec![MediaKind::Video, MediaKind::Audio]
+}
+
+/// Tells which links are downloaded, how, & in what form.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub name: String,
+    hosts: Vec<String>,
+    #[serde(default = "any_path")]
+    path: String,
+    #[serde(default)]
+    query: Vec<String>,
+    canonical: String,
+    /// Names of the backends, as in [`super::Backend::name`]; empty if any backend will do.
+    #[serde(default)]
+    pub backends: Vec<String>,
+    #[serde(default = "yes")]
+    pub enabled: bool,
+    #[serde(default = "all_kinds")]
+    pub kinds: Vec<MediaKind>,
+}
+
+impl Rule {
+    fn matches_host(&self, host: &str) -> bool {
+        self.hosts.iter().any(|pattern| match pattern.strip_prefix("*.") {
+            Some(domain) => host.strip_suffix(domain).is_some_and(|sub| sub.ends_with('.')),
+            None => pattern == host,
+        })
+    }
+
+    /// Appends the segments captured from `path` to `captures`, unless `path` doesn't match.
+    fn match_path<'a>(&'a self, path: &'a str, captures: &mut Vec<(&'a str, &'a str)>) -> bool {
+        let mut rest = Some(path.strip_prefix('/').unwrap_or(path));
+        for segment in self.path.strip_prefix('/').unwrap_or(&self.path).split('/') {
+            let Some(path) = rest.filter(|path| !path.is_empty()) else { return false };
+            if let Some(name) = segment.strip_prefix("{*").and_then(|x| x.strip_suffix('}')) {
+                captures.push((name, path));
+                return true;
+            }
+            let (head, tail) = match path.split_once('/') {
+                Some((head, tail)) => (head, Some(tail)),
+                None => (path, None),
+            };
+            match segment.strip_prefix('{').and_then(|x| x.strip_suffix('}')) {
+                Some(name) => captures.push((name, head)),
+                None if segment == head => (),
+                None => return false,
+            }
+            rest = tail;
+        }
+        // A trailing slash is allowed
+        rest.is_none_or(str::is_empty)
+    }
+
+    /// Returns the names of the captures available to the canonical link.
+    fn capture_names(&self) -> impl Iterator<Item = &str> {
+        let path = self.path.split('/').filter_map(|segment| {
+            segment.strip_prefix('{')?.strip_suffix('}').map(|x| x.trim_start_matches('*'))
+        });
+        path.chain(self.query.iter().map(String::as_str)).chain(["host"])
+    }
+
+    /// Substitutes `captures` into the canonical link.
+    fn render(&self, captures: &[(&str, &str)]) -> Option<String> {
+        let mut res = String::with_capacity(self.canonical.len());
+        let mut rest = &*self.canonical;
+        while let Some((before, after)) = rest.split_once('{') {
+            let (name, after) = after.split_once('}')?;
+            res += before;
+            res += captures.iter().find(|(x, _)| *x == name)?.1;
+            rest = after;
+        }
+        res += rest;
+        Some(res)
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Routes {
+    #[serde(rename = "rule", default)]
+    rules: Vec<Rule>,
+}
+
+impl Routes {
+    /// Reads the table from `routes.toml` in the working directory, or uses the default one if
+    /// there's no such file. `backends` are the names of the backends the rules may refer to.
+    pub fn load(backends: &[&str]) -> Result<Self> {
+        let (src, path) = match std::fs::read_to_string(PATH) {
+            Ok(src) => (src, PATH),
+            Err(e) if e.kind() == NotFound => (DEFAULT.to_owned(), "the default routes"),
+            Err(e) => Err(e)?,
+        };
+        let res: Self = toml::from_str(&src).map_err(|e| format!("{path}: {e}"))?;
+        for rule in &res.rules {
+            let mut rest = &*rule.canonical;
+            while let Some((_, after)) = rest.split_once('{') {
+                let (name, after) = after.split_once('}').unwrap_or((after, ""));
+                if !rule.capture_names().any(|x| x == name) {
+                    Err(format!("{path}: {:?} isn't captured by rule {:?}", name, rule.name))?;
+                }
+                rest = after;
+            }
+            if let Some(name) = rule.backends.iter().find(|x| !backends.contains(&x.as_str())) {
+                Err(format!("{path}: rule {:?} refers to an unknown backend {name:?}", rule.name))?;
+            }
+        }
+        log::info!("Loaded {} routes from {path}", res.rules.len());
+        Ok(res)
+    }
+
+    /// Returns the first rule matching `uri` & the canonical link produced by it.
+    /// The rule may be disabled.
+    pub fn route(&self, uri: &Uri) -> Option<(&Rule, String)> {
+        let host = uri.host()?;
+        let query: Vec<_> = uri.query()
+            .unwrap_or_default()
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .collect();
+        self.rules.iter().find_map(|rule| {
+            if !rule.matches_host(host) {
+                return None;
+            }
+            let mut captures = vec![("host", host)];
+            if !rule.match_path(uri.path(), &mut captures) {
+                return None;
+            }
+            for name in &rule.query {
+                captures.push(*query.iter().find(|(x, _)| *x == *name)?);
+            }
+            Some((rule, rule.render(&captures)?))
+        })
+    }
+}
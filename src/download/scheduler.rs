@@ -0,0 +1,69 @@
+use {
+    super::Error,
+    std::{collections::HashMap, sync::Arc},
+    tokio::sync::{OwnedSemaphorePermit, Semaphore},
+};
+
+/// Bounds on how many downloads are done at once.
+#[derive(Debug, Clone)]
+pub struct JobLimits {
+    /// Downloads running at once, across all the backends.
+    pub max_jobs: usize,
+    /// Downloads waiting for a free slot; any downloads past that are rejected with
+    /// [`Error::Busy`].
+    pub max_queued: usize,
+    /// Downloads running at once via a particular backend, identified by [`super::Backend::name`].
+    /// Backends not listed here are only bound by `max_jobs`.
+    pub per_backend: Vec<(&'static str, usize)>,
+}
+
+impl Default for JobLimits {
+    fn default() -> Self {
+        Self { max_jobs: 4, max_queued: 16, per_backend: vec![("yt-dlp", 3)] }
+    }
+}
+
+/// Permission to run a download, held until the download is finished.
+pub struct Job {
+    _permits: Vec<OwnedSemaphorePermit>,
+}
+
+pub struct Scheduler {
+    /// Permits for both running & waiting downloads.
+    slots: Arc<Semaphore>,
+    running: Arc<Semaphore>,
+    backends: HashMap<&'static str, Arc<Semaphore>>,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new(&JobLimits::default())
+    }
+}
+
+impl Scheduler {
+    pub fn new(limits: &JobLimits) -> Self {
+        Self {
+            slots: Arc::new(Semaphore::new(limits.max_jobs.saturating_add(limits.max_queued))),
+            running: Arc::new(Semaphore::new(limits.max_jobs)),
+            backends: limits.per_backend
+                .iter()
+                .map(|&(name, max_jobs)| (name, Arc::new(Semaphore::new(max_jobs))))
+                .collect(),
+        }
+    }
+
+    /// Waits for the download via `backend` to be allowed to run, unless too many downloads are
+    /// waiting already.
+    pub async fn start(&self, backend: &str) -> Result<Job, Error> {
+        let slot = self.slots.clone().try_acquire_owned().map_err(|_| Error::Busy)?;
+        let mut permits = vec![slot];
+        // The backend's permit is acquired first so that a busy backend doesn't occupy the slots
+        // that other backends could use.
+        if let Some(semaphore) = self.backends.get(backend) {
+            permits.push(semaphore.clone().acquire_owned().await.map_err(|_| Error::Busy)?);
+        }
+        permits.push(self.running.clone().acquire_owned().await.map_err(|_| Error::Busy)?);
+        Ok(Job { _permits: permits })
+    }
+}
@@ -1,11 +1,10 @@
 use {
-    super::{Error, MediaKind, CACHE_DIR, MAX_FILESIZE},
-    crate::utils::Result,
-    axum::body::Bytes,
-    futures::{Stream, StreamExt},
+    super::{Backend, Error, FormatInfo, Info, Media, MediaKind, Metadata, Options, Quality},
+    futures::{future::BoxFuture, FutureExt, TryStreamExt},
     serde::Deserialize,
-    std::{borrow::Cow, pin::Pin, process::{Output, Stdio}, task::{Context, Poll}},
-    tokio::{fs, io::AsyncReadExt, process::{ChildStdout, Command}},
+    serde_json::Value,
+    std::{borrow::Cow, collections::BTreeMap, process::{Output, Stdio}, time::Duration},
+    tokio::{fs, process::Command, time::{self, Instant}},
     tokio_util::io::ReaderStream,
 };
 
@@ -14,108 +13,340 @@ struct MediaData<'src> {
     id: Cow<'src, str>,
     format_id: Cow<'src, str>,
     title: Cow<'src, str>,
+    /// Extension of the stream as it's downloaded, before any conversion.
+    ext: Option<Cow<'src, str>>,
     filesize: Option<usize>,
+    duration: Option<f64>,
     #[serde(default)]
     is_live: bool,
 }
 
-pub struct Media {
-    inner: Result<ReaderStream<ChildStdout>, Option<Bytes>>,
-    filesize: usize,
-    pub filename: String,
+#[derive(Debug, Deserialize)]
+struct FormatData {
+    vcodec: Option<String>,
+    acodec: Option<String>,
+    height: Option<u16>,
+    /// Audio bitrate, in kbps.
+    abr: Option<f64>,
+    filesize: Option<usize>,
+    filesize_approx: Option<usize>,
 }
 
-impl Stream for Media {
-    type Item = Result<Bytes>;
-
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
-        match &mut self.inner {
-            Ok(stream) => stream.poll_next_unpin(cx).map_err(Into::into),
-            Err(bytes) => bytes.take().map(Ok).into(),
+impl FormatData {
+    const fn filesize(&self) -> Option<usize> {
+        match self.filesize {
+            Some(size) => Some(size),
+            None => self.filesize_approx,
         }
     }
+}
+
+#[derive(Debug, Deserialize)]
+struct InfoData {
+    #[serde(rename = "_type")]
+    kind: Option<String>,
+    title: String,
+    uploader: Option<String>,
+    duration: Option<f64>,
+    thumbnail: Option<String>,
+    is_live: Option<bool>,
+    #[serde(default)]
+    formats: Vec<FormatData>,
+    #[serde(default)]
+    entries: Vec<serde::de::IgnoredAny>,
+}
 
-    fn size_hint(&self) -> (usize, Option<usize>) {
-        (self.filesize, Some(self.filesize))
+/// Determines the cause of a failure from the error message printed by `yt-dlp`.
+fn classify(stderr: &str) -> Error {
+    let msg = stderr.lines().rev().find_map(|line| line.strip_prefix("ERROR: ")).unwrap_or(stderr);
+    let lowercase = msg.to_lowercase();
+    let mentions = |patterns: &[&str]| patterns.iter().any(|p| lowercase.contains(p));
+    let msg = || msg.trim().into();
+
+    if mentions(&["truncated", "incomplete youtube id", "http error 404", "does not exist"]) {
+        Error::NotFound
+    } else if mentions(&["unsupported url"]) {
+        Error::UnsupportedUrl(msg())
+    } else if mentions(&["private video", "video is private", "this account is private"]) {
+        Error::Private(msg())
+    } else if mentions(&["members-only", "members only", "join this channel"]) {
+        Error::MembersOnly(msg())
+    } else if mentions(&["confirm your age", "age-restricted", "inappropriate for some users"]) {
+        Error::AgeRestricted(msg())
+    } else if mentions(&["http error 429", "too many requests", "rate-limit", "not a bot"]) {
+        Error::RateLimited(msg())
+    } else if mentions(&["your country", "geo restrict", "geo-restrict", "your location"]) {
+        Error::GeoBlocked(msg())
+    } else if mentions(&["login required", "log in", "sign in", "--cookies", "authentication"]) {
+        Error::LoginRequired(msg())
+    } else if mentions(&["removed", "terminated", "no longer available", "video unavailable"]) {
+        Error::Removed(msg())
+    } else {
+        Error::MetadataFetchFailed
     }
 }
 
-impl Media {
-    pub async fn get(uri: String, mkind: MediaKind) -> Result<Self, Error> {
-        let mut cmd = Command::new("yt-dlp");
-        let bytes = match cmd
-            .args((mkind == MediaKind::Audio).then_some("-x"))
-            .args(["--no-download", "-J", &uri])
-            .output().await
-        {
-            Ok(Output { status, stderr, stdout }) => if status.success() {
-                stdout
+/// Downloads media by invoking `yt-dlp`, which is expected to be in `$PATH`.
+pub struct YtDlp {
+    /// How long a single invocation of `yt-dlp` may take, after which it's killed.
+    pub timeout: Duration,
+    /// Where the media of unknown size is written to before being sent, with a trailing slash.
+    pub tmp_dir: String,
+    /// In bytes.
+    pub max_filesize: usize,
+}
+
+impl YtDlp {
+    /// Runs `cmd`, which is expected to print the data about the media as JSON, returning it.
+    async fn dump_json(&self, cmd: &mut Command) -> Result<Vec<u8>, Error> {
+        cmd.kill_on_drop(true);
+        match time::timeout(self.timeout, cmd.output()).await {
+            Err(_) => {
+                log::error!("`yt-dlp` timed out while getting the video data:\ncommand: {cmd:?}");
+                Err(Error::MetadataFetchFailed)
+            }
+            Ok(Ok(Output { status, stderr, stdout })) => if status.success() {
+                Ok(stdout)
             } else {
-                return Err(if stderr.ends_with(b"truncated.\n") {
-                    Error::NotFound
-                } else {
-                    log::error!("`yt-dlp` exited unsuccessfully while downloading the video:\n\
-                                command: {cmd:?}\n\
-                                stderr:\n{}",
-                                String::from_utf8_lossy(&stderr));
-                    Error::MetadataFetchFailed
-                })
+                let stderr = String::from_utf8_lossy(&stderr);
+                let err = classify(&stderr);
+                match err.message() {
+                    Some(msg) => log::info!("`yt-dlp` couldn't get the video data: {msg}"),
+                    None if err == Error::NotFound => (),
+                    None => log::error!(
+                        "`yt-dlp` exited unsuccessfully while downloading the video:\n\
+                        command: {cmd:?}\n\
+                        stderr:\n{stderr}"
+                    ),
+                }
+                Err(err)
             }
-            Err(err) => {
+            Ok(Err(err)) => {
                 log::error!("failed to launch `yt-dlp` to get the video data: {err}");
+                Err(Error::MetadataFetchFailed)
+            }
+        }
+    }
+
+    /// Fetches the data about the media that both the metadata & the info are made of.
+    /// If the link points to a playlist and no item is selected in `options`, the entries of the
+    /// playlist aren't resolved.
+    async fn fetch_data(&self, uri: &str, options: Options) -> Result<Value, Error> {
+        let mkind = options.format.kind();
+        let mut cmd = Command::new("yt-dlp");
+        cmd
+            .args((mkind == MediaKind::Audio).then_some("-x"))
+            .args(["-f", &options.quality.format_selector(mkind)])
+            .args(match options.item {
+                Some(item) => vec!["--playlist-items".to_owned(), item.to_string()],
+                None => vec!["--flat-playlist".to_owned()],
+            })
+            .args(["--no-download", "-J", uri]);
+        let bytes = self.dump_json(&mut cmd).await?;
+        serde_json::from_slice(&bytes).map_err(|err| {
+            log::error!("failed to decode video data as JSON: {err}");
+            Error::MetadataFetchFailed
+        })
+    }
+
+    /// If the link points to a playlist and no item is selected in `options`, only the title and
+    /// the length of the playlist are fetched.
+    async fn fetch_metadata(&self, uri: &str, options: Options) -> Result<Metadata, Error> {
+        let mut data = self.fetch_data(uri, options).await?;
+        if data["_type"] == "playlist" {
+            let Value::Array(entries) = data["entries"].take() else {
+                log::error!("no entries in the playlist data of {uri:?}");
                 return Err(Error::MetadataFetchFailed);
+            };
+            if options.item.is_none() {
+                return Ok(Metadata {
+                    title: data["title"].as_str().unwrap_or("playlist").to_owned(),
+                    playlist_len: Some(entries.len()),
+                    ..Metadata::default()
+                });
             }
-        };
+            data = entries.into_iter().next().ok_or(Error::NotFound)?;
+        }
 
-        let MediaData { id, format_id, title, filesize, is_live } = serde_json::from_slice(&bytes)
-            .map_err(|err| {
-                log::error!("failed to decode video data as JSON: {err}");
+        #[cfg(debug_assertions)]
+        let json = data.to_string();
+        let MediaData { id, format_id, title, ext, filesize, duration, is_live } =
+            MediaData::deserialize(data).map_err(|err| {
+                log::error!("failed to decode video data: {err}");
                 Error::MetadataFetchFailed
             })?;
 
         #[cfg(debug_assertions)]
-        fs::write(format!("{CACHE_DIR}{id}.json"), &bytes).await
+        fs::write(format!("{}{id}.json", self.tmp_dir), json).await
             .map_err(|_| Error::MetadataFetchFailed)?;
 
-        if is_live {
-            return Err(Error::IsStream);
+        // The size of the stream says little about the size of the media converted from it, which is
+        // then only known once it's downloaded.
+        let converted = ext.as_deref() != Some(options.format.extension());
+        Ok(Metadata {
+            title: title.into_owned(),
+            filesize: if converted { None } else { filesize },
+            duration: duration.and_then(|d| Duration::try_from_secs_f64(d).ok()),
+            is_live,
+            stream_id: format_id.into_owned(),
+            playlist_len: None,
+            format: None,
+        })
+    }
+
+    /// Made of the same data as the metadata of the whole media at `uri`, see [`Self::fetch_data`].
+    async fn fetch_info(&self, uri: &str) -> Result<Info, Error> {
+        let data = self.fetch_data(uri, Options::new(MediaKind::Video)).await?;
+        let InfoData { kind, title, uploader, duration, thumbnail, is_live, formats, entries } =
+            InfoData::deserialize(data).map_err(|err| {
+                log::error!("failed to decode video data: {err}");
+                Error::MetadataFetchFailed
+            })?;
+        if kind.as_deref() == Some("playlist") {
+            let playlist_len = Some(entries.len());
+            return Ok(Info { title, uploader, playlist_len, ..Info::default() });
         }
 
+        let audio_size = |format: &FormatData| {
+            (format.acodec.as_deref() == Some("none")).then(|| formats.iter()
+                .filter(|f| f.vcodec.as_deref() == Some("none"))
+                .filter_map(FormatData::filesize)
+                .max())
+                .flatten()
+                .unwrap_or_default()
+        };
+        // Only the largest format of each quality is listed.
+        let mut videos = BTreeMap::<u16, Option<usize>>::new();
+        let mut tracks = BTreeMap::<u16, Option<usize>>::new();
+        for format in &formats {
+            let (qualities, quality, size) = match (format.vcodec.as_deref(), format) {
+                (Some("none"), FormatData { abr: Some(abr), .. }) => {
+                    #[expect(
+                        clippy::cast_possible_truncation,
+                        clippy::cast_sign_loss,
+                        reason = "bitrates are well within the range of `u16`"
+                    )]
+                    let abr = abr.round() as u16;
+                    (&mut tracks, abr, format.filesize())
+                }
+                (Some(vcodec), FormatData { height: Some(height), .. }) if vcodec != "none" => {
+                    let size = format.filesize().map(|size| size + audio_size(format));
+                    (&mut videos, *height, size)
+                }
+                _ => continue,
+            };
+            let max = qualities.entry(quality).or_default();
+            *max = (*max).max(size);
+        }
+
+        let videos = videos.into_iter().rev().map(|(height, filesize)| FormatInfo {
+            kind: MediaKind::Video,
+            quality: Quality::Height(height).to_string(),
+            filesize,
+        });
+        let tracks = tracks.into_iter().rev().map(|(bitrate, filesize)| FormatInfo {
+            kind: MediaKind::Audio,
+            quality: Quality::Bitrate(bitrate).to_string(),
+            filesize,
+        });
+        Ok(Info {
+            title,
+            uploader,
+            duration: duration
+                .and_then(|d| Duration::try_from_secs_f64(d).ok())
+                .map(|d| d.as_secs()),
+            thumbnail,
+            is_live: is_live.unwrap_or_default(),
+            playlist_len: None,
+            formats: videos.chain(tracks).collect(),
+        })
+    }
+
+    async fn fetch_media(&self, uri: &str, options: Options, metadata: &Metadata)
+        -> Result<Media, Error>
+    {
+        let Options { format, quality, clip, item } = options;
+        let deadline = Instant::now() + self.timeout;
         let mut cmd = Command::new("yt-dlp");
         cmd
+            .kill_on_drop(true)
             .stdout(Stdio::piped())
             .stderr(Stdio::null())
-            .args(match mkind {
-                MediaKind::Video => &["--recode-video", "mp4"][..],
-                MediaKind::Audio => &["--audio-format", "mp3", "-x"][..],
-            })
+            .args(format.yt_dlp_args())
+            .args(match quality {
+                Quality::Bitrate(bitrate) if format.kind() == MediaKind::Audio => {
+                    Some(["--audio-quality".to_owned(), format!("{bitrate}K")])
+                }
+                _ => None,
+            }.into_iter().flatten())
+            .args((!clip.is_full()).then(|| {
+                let start = clip.start.unwrap_or_default().as_secs_f64();
+                let end = clip.end
+                    .map_or_else(|| "inf".to_owned(), |end| end.as_secs_f64().to_string());
+                ["--download-sections".to_owned(), format!("*{start}-{end}")]
+            }).into_iter().flatten())
+            .args(item.map(|item| ["--playlist-items".to_owned(), item.to_string()])
+                .into_iter().flatten())
             .args([
-                "-f", &format_id,
+                "-f", &metadata.stream_id,
                 "--embed-metadata",
                 "--embed-thumbnail",
                 "-o", "-",
-                &uri,
+                uri,
             ]);
         let mut yt_dlp = cmd.spawn().map_err(|e| {
             log::error!("Failed to download media\ncommand: {cmd:#?}\ncause: {e}");
             Error::DataFetchFailed
         })?;
-        let mut stdout = yt_dlp.stdout.take().ok_or_else(|| {
+        let stdout = yt_dlp.stdout.take().ok_or_else(|| {
             log::error!("Failed to get the stdout of `yt-dlp`");
             Error::DataFetchFailed
         })?;
 
-        let filename = format!("{title}.{}", mkind.extension());
-        Ok(if let Some(filesize) = filesize {
-            if filesize >= MAX_FILESIZE {
-                return Err(Error::TooLarge);
-            }
-            Self { inner: Ok(ReaderStream::new(stdout)), filename, filesize }
-        } else {
-            let mut bytes = vec![];
-            let filesize = stdout.read_to_end(&mut bytes).await
-                .map_err(|_| Error::DataFetchFailed)?;
-            Self { inner: Err(Some(bytes.into())), filesize, filename }
-        })
+        let Some(filesize) = metadata.filesize else {
+            // The whole media has to be written to disk before sending it, so it's done here.
+            let res = time::timeout_at(deadline, async {
+                let media = Media::spill(stdout, &self.tmp_dir, self.max_filesize).await?;
+                match yt_dlp.wait().await {
+                    Ok(status) if status.success() => Ok(media),
+                    Ok(status) => {
+                        log::error!("`yt-dlp` exited unsuccessfully: {status}\ncommand: {cmd:?}");
+                        Err(Error::DataFetchFailed)
+                    }
+                    Err(err) => {
+                        log::error!("Failed to wait for `yt-dlp`: {err}");
+                        Err(Error::DataFetchFailed)
+                    }
+                }
+            });
+            return res.await.unwrap_or_else(|_| {
+                log::error!("`yt-dlp` timed out while downloading media\ncommand: {cmd:?}");
+                Err(Error::DataFetchFailed)
+            });
+        };
+        Ok(Media::new(ReaderStream::new(stdout).map_err(Into::into), filesize)
+            .with_process(yt_dlp, deadline))
+    }
+}
+
+impl Backend for YtDlp {
+    fn name(&self) -> &'static str {
+        "yt-dlp"
+    }
+
+    fn metadata<'a>(&'a self, uri: &'a str, options: Options)
+        -> BoxFuture<'a, Result<Metadata, Error>>
+    {
+        self.fetch_metadata(uri, options).boxed()
+    }
+
+    fn fetch<'a>(&'a self, uri: &'a str, options: Options, metadata: &'a Metadata)
+        -> BoxFuture<'a, Result<Media, Error>>
+    {
+        self.fetch_media(uri, options, metadata).boxed()
+    }
+
+    fn info<'a>(&'a self, uri: &'a str) -> BoxFuture<'a, Result<Info, Error>> {
+        self.fetch_info(uri).boxed()
     }
 }
@@ -0,0 +1,177 @@
+//! Streaming of playlists as uncompressed ZIP archives.
+//! The sizes & checksums of the entries aren't known in advance, so they're written after the data
+//! of each entry, in a data descriptor. Zip64 isn't supported, hence the playlists are limited
+//! to 4GB in total.
+
+use {
+    super::Playlist,
+    crate::utils::Result,
+    axum::body::Bytes,
+    futures::{channel::mpsc, SinkExt, Stream, StreamExt},
+};
+
+const LOCAL_HEADER_SIG: u32 = 0x0403_4b50;
+const DATA_DESCRIPTOR_SIG: u32 = 0x0807_4b50;
+const CENTRAL_HEADER_SIG: u32 = 0x0201_4b50;
+const END_OF_CENTRAL_DIR_SIG: u32 = 0x0605_4b50;
+/// Version 2.0, the lowest one that supports data descriptors.
+const VERSION: u16 = 20;
+/// Bit 3: sizes & CRC are in the data descriptor; bit 11: the filename is UTF-8.
+const FLAGS: u16 = 1 << 3 | 1 << 11;
+/// 1980-01-01 in MS-DOS format, the earliest date representable.
+const DATE: u16 = 0x21;
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        #[expect(clippy::cast_possible_truncation, reason = "i < 256")]
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 0 { crc >> 1 } else { crc >> 1 ^ 0xEDB8_8320 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32(mut crc: u32, data: &[u8]) -> u32 {
+    crc = !crc;
+    for &byte in data {
+        crc = CRC_TABLE[((crc ^ u32::from(byte)) & 0xFF) as usize] ^ crc >> 8;
+    }
+    !crc
+}
+
+struct Entry {
+    name: String,
+    crc: u32,
+    size: u32,
+    offset: u32,
+}
+
+#[derive(Default)]
+struct Writer {
+    buf: Vec<u8>,
+    /// Number of bytes written before `buf`.
+    offset: usize,
+}
+
+impl Writer {
+    fn u16(&mut self, x: u16) -> &mut Self {
+        self.buf.extend_from_slice(&x.to_le_bytes());
+        self
+    }
+
+    fn u32(&mut self, x: u32) -> &mut Self {
+        self.buf.extend_from_slice(&x.to_le_bytes());
+        self
+    }
+
+    fn bytes(&mut self, x: &[u8]) -> &mut Self {
+        self.buf.extend_from_slice(x);
+        self
+    }
+
+    const fn pos(&self) -> usize {
+        self.offset + self.buf.len()
+    }
+
+    fn take(&mut self) -> Bytes {
+        self.offset += self.buf.len();
+        std::mem::take(&mut self.buf).into()
+    }
+
+    fn local_header(&mut self, name: &str) -> &mut Self {
+        self.u32(LOCAL_HEADER_SIG).u16(VERSION).u16(FLAGS).u16(0)
+            .u16(0).u16(DATE)
+            .u32(0).u32(0).u32(0)
+            .u16(name.len().try_into().unwrap_or(u16::MAX)).u16(0)
+            .bytes(&name.as_bytes()[..name.len().min(u16::MAX.into())])
+    }
+
+    fn data_descriptor(&mut self, entry: &Entry) -> &mut Self {
+        self.u32(DATA_DESCRIPTOR_SIG).u32(entry.crc).u32(entry.size).u32(entry.size)
+    }
+
+    fn central_header(&mut self, entry: &Entry) -> &mut Self {
+        let name = &entry.name.as_bytes()[..entry.name.len().min(u16::MAX.into())];
+        self.u32(CENTRAL_HEADER_SIG).u16(VERSION).u16(VERSION).u16(FLAGS).u16(0)
+            .u16(0).u16(DATE)
+            .u32(entry.crc).u32(entry.size).u32(entry.size)
+            .u16(name.len().try_into().unwrap_or(u16::MAX)).u16(0).u16(0)
+            .u16(0).u16(0).u32(0)
+            .u32(entry.offset)
+            .bytes(name)
+    }
+
+    fn end_of_central_dir(&mut self, n_entries: usize, start: usize) -> Result {
+        let n_entries: u16 = n_entries.try_into()?;
+        let size: u32 = (self.pos() - start).try_into()?;
+        self.u32(END_OF_CENTRAL_DIR_SIG).u16(0).u16(0)
+            .u16(n_entries).u16(n_entries)
+            .u32(size).u32(start.try_into()?)
+            .u16(0);
+        Ok(())
+    }
+}
+
+/// Downloads the entries of `playlist` one by one, concatenating them into a ZIP archive.
+/// The entries that failed to download are skipped, as are the ones past the total size limit.
+pub fn stream(mut playlist: Playlist) -> impl Stream<Item = Result<Bytes>> + Send {
+    let (mut tx, rx) = mpsc::channel(4);
+    tokio::spawn(async move {
+        let res: Result = async {
+            let mut w = Writer::default();
+            let mut entries = vec![];
+            let width = playlist.len().to_string().len();
+            let mut index = 0;
+            while let Some(res) = playlist.next().await {
+                index += 1;
+                let mut media = match res {
+                    Ok(media) => media,
+                    Err(err) => {
+                        log::warn!("Skipping entry #{index} of {:?}: {err:?}", playlist.title);
+                        continue;
+                    }
+                };
+
+                let name = format!("{index:0width$} - {}", media.filename().replace('/', "_"));
+                let offset = w.pos().try_into()?;
+                tx.send(Ok(w.local_header(&name).take())).await?;
+                let (mut crc, mut size) = (0, 0usize);
+                while let Some(chunk) = media.next().await {
+                    let chunk = chunk?;
+                    crc = crc32(crc, &chunk);
+                    size += chunk.len();
+                    w.offset += chunk.len();
+                    tx.send(Ok(chunk)).await?;
+                }
+                drop(media);
+                let entry = Entry { name, crc, size: size.try_into()?, offset };
+                tx.send(Ok(w.data_descriptor(&entry).take())).await?;
+                entries.push(entry);
+            }
+            if playlist.exceeds_max_size() {
+                log::warn!("Playlist {:?} is too large, truncating it", playlist.title);
+            }
+
+            let start = w.pos();
+            for entry in &entries {
+                w.central_header(entry);
+            }
+            w.end_of_central_dir(entries.len(), start)?;
+            tx.send(Ok(w.take())).await?;
+            Ok(())
+        }.await;
+
+        if let Err(err) = res {
+            log::error!("Failed to stream playlist {:?} as a ZIP archive: {err}", playlist.title);
+            _ = tx.send(Err(err)).await;
+        }
+    });
+    rx
+}
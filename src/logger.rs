@@ -1,23 +1,61 @@
+use {
+    crate::{config, utils::Result},
+    log::{logger, set_boxed_logger, set_max_level, LevelFilter, Log},
+    std::{
+        fs::File,
+        io::{self, Write},
+        sync::{Mutex, PoisonError},
+    },
+};
+#[cfg(feature = "telegram-logs")]
 use {
     crate::{bot::{telegram::{SendMessage, MAX_MSG_LEN}, Bot}, utils::{default, LimitedFormatter}},
     futures::executor::block_on,
-    log::{logger, max_level, set_boxed_logger, set_max_level, LevelFilter, Log, SetLoggerError},
+    log::max_level,
     std::{
         fmt::Write as _,
-        io::{Cursor, Write as _},
+        io::Cursor,
         iter,
         str::from_utf8_unchecked,
-        sync::{atomic::Ordering::Relaxed, Arc, Mutex, MutexGuard},
+        sync::{atomic::Ordering::Relaxed, Arc, MutexGuard},
     },
     tokio::spawn,
 };
 
+/// Writes the records to stderr or to a file as they come.
+struct StreamLogger {
+    output: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Log for StreamLogger {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        _ = writeln!(
+            self.output.lock().unwrap_or_else(PoisonError::into_inner),
+            "[{}] {}: {}",
+            record.level(),
+            record.module_path().unwrap_or(""),
+            record.args(),
+        );
+    }
+
+    fn flush(&self) {
+        _ = self.output.lock().unwrap_or_else(PoisonError::into_inner).flush();
+    }
+}
+
+/// Keeps the records until the bot's owner requests them.
+#[cfg(feature = "telegram-logs")]
 struct Logger {
     /// All records must be less than [`MAX_MSG_LEN`]
     records: Arc<Mutex<heapless::Deque<Box<str>, 100>>>,
     bot: Arc<Bot>,
 }
 
+#[cfg(feature = "telegram-logs")]
 fn get_records(unlocked: &Mutex<heapless::Deque<Box<str>, 100>>)
     -> MutexGuard<heapless::Deque<Box<str>, 100>>
 {
@@ -31,6 +69,7 @@ fn get_records(unlocked: &Mutex<heapless::Deque<Box<str>, 100>>)
     })
 }
 
+#[cfg(feature = "telegram-logs")]
 impl Log for Logger {
     fn enabled(&self, _: &log::Metadata) -> bool {
         true
@@ -122,9 +161,23 @@ impl Log for Logger {
     }
 }
 
-pub fn init(bot: Arc<Bot>) -> Result<(), SetLoggerError> {
+/// Sends the logs to the owner of `bot`.
+#[cfg(feature = "telegram-logs")]
+pub fn init_telegram(bot: Arc<Bot>) -> Result {
+    set_max_level(LevelFilter::Warn);
+    set_boxed_logger(Box::new(Logger { records: default(), bot }))?;
+    Ok(())
+}
+
+/// Writes the logs to the file in `config`, or to stderr if there's none.
+pub fn init(config: &config::Log) -> Result {
+    let output: Box<dyn Write + Send> = match &config.file {
+        Some(path) => Box::new(File::options().create(true).append(true).open(path)?),
+        None => Box::new(io::stderr()),
+    };
     set_max_level(LevelFilter::Warn);
-    set_boxed_logger(Box::new(Logger { records: default(), bot }))
+    set_boxed_logger(Box::new(StreamLogger { output: Mutex::new(output) }))?;
+    Ok(())
 }
 
 pub fn deinit() {
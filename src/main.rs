@@ -1,4 +1,10 @@
+#[cfg(not(any(feature = "website", feature = "bot")))]
+compile_error!("at least one of the `website` & `bot` features must be enabled");
+
+#[cfg(feature = "bot")]
 mod bot;
+mod config;
+#[cfg(feature = "website")]
 mod website;
 mod download;
 mod utils;
@@ -6,40 +12,83 @@ mod stats;
 mod logger;
 
 use {
-    axum::{middleware, routing::{get, post}, serve},
+    axum::serve,
+    config::{Config, UpdateMode},
     futures::TryFutureExt,
-    stats::{record_audio_downloader, record_video_downloader, record_website_visitor, Stats},
-    std::net::{Ipv4Addr, SocketAddr},
+    std::net::SocketAddr,
     tokio::{net::TcpListener, signal::ctrl_c},
+    utils::Result,
+};
+#[cfg(any(feature = "website", feature = "bot"))]
+use {download::Downloader, stats::Stats, std::sync::Arc};
+#[cfg(feature = "bot")]
+use axum::routing::post;
+#[cfg(feature = "website")]
+use {
+    axum::{middleware, routing::get},
+    stats::{record_audio_downloader, record_video_downloader, record_website_visitor},
     tower::ServiceBuilder,
     tower_http::services::ServeDir,
-    utils::Result,
 };
 
 #[tokio::main]
 async fn main() -> Result {
-    let stats = Stats::default();
-    let bot = bot::init(stats.clone()).await?;
-    logger::init(bot.clone())?;
-    
-    let router = axum::Router::new()
-        .route("/bot", post(bot::handle_update))
-        .route("/video", get(website::serve_video)
-            .layer(middleware::from_fn_with_state(stats.clone(), record_video_downloader)))
-        .route("/audio", get(website::serve_audio)
-            .layer(middleware::from_fn_with_state(stats.clone(), record_audio_downloader)))
-        .fallback_service(ServiceBuilder::new()
-            .layer(middleware::from_fn_with_state(stats.clone(), record_website_visitor)) 
-            .service(ServeDir::new("dist")
-                .not_found_service(serve_embedded_html!("../dist/404.html"))))
-        .with_state(bot.clone())
-        .into_make_service_with_connect_info::<SocketAddr>();
-
-    log::info!("Starting a server on {}", env!("URL"));
-    serve(TcpListener::bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 8443)).await?, router)
-        .with_graceful_shutdown(ctrl_c().unwrap_or_else(drop))
-        .await?;
-    bot::deinit(bot).await?;
+    let config = Config::load()?;
+    // Unused by the builds rejected above; gated so that the error isn't buried in warnings.
+    #[cfg(any(feature = "website", feature = "bot"))]
+    let (stats, downloader) = (Stats::default(), Arc::new(Downloader::new(&config)?));
+    #[cfg_attr(not(any(feature = "website", feature = "bot")), expect(unused_mut))]
+    let mut router = axum::Router::new();
+
+    #[cfg(feature = "bot")]
+    let bot = if config.telegram.enabled {
+        let bot = bot::init(&config, stats.clone(), downloader.clone()).await?;
+        if config.telegram.updates == UpdateMode::Webhook {
+            router = router.route("/bot", post(bot::handle_update).with_state(bot.clone()));
+        }
+        Some(bot)
+    } else {
+        None
+    };
+
+    #[cfg(feature = "telegram-logs")]
+    match bot.clone().filter(|_| config.log.telegram) {
+        Some(bot) => logger::init_telegram(bot)?,
+        None => logger::init(&config.log)?,
+    }
+    #[cfg(not(feature = "telegram-logs"))]
+    logger::init(&config.log)?;
+
+    #[cfg(feature = "website")]
+    if config.website.enabled {
+        router = router
+            .route("/video", get(website::serve_video)
+                .with_state(downloader.clone())
+                .layer(middleware::from_fn_with_state(stats.clone(), record_video_downloader)))
+            .route("/audio", get(website::serve_audio)
+                .with_state(downloader.clone())
+                .layer(middleware::from_fn_with_state(stats.clone(), record_audio_downloader)))
+            .route("/info", get(website::serve_info).with_state(downloader.clone()))
+            .fallback_service(ServiceBuilder::new()
+                .layer(middleware::from_fn_with_state(stats.clone(), record_website_visitor))
+                .service(ServeDir::new("dist")
+                    .not_found_service(serve_embedded_html!("../dist/404.html"))));
+    }
+
+    let webhook = config.telegram.enabled && config.telegram.updates == UpdateMode::Webhook;
+    if config.website.enabled || webhook {
+        log::info!("Starting a server on {} ({})", config.url, config.bind);
+        let router = router.into_make_service_with_connect_info::<SocketAddr>();
+        serve(TcpListener::bind(config.bind).await?, router)
+            .with_graceful_shutdown(ctrl_c().unwrap_or_else(drop))
+            .await?;
+    } else {
+        ctrl_c().await?;
+    }
+    #[cfg(feature = "bot")]
+    if let Some(bot) = bot {
+        bot::deinit(bot).await?;
+    }
     logger::deinit();
 
     Ok(())
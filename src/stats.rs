@@ -1,11 +1,15 @@
 use std::{
     collections::HashSet,
     fmt::{Display, Formatter},
-    net::{IpAddr, SocketAddr},
+    net::IpAddr,
     sync::{Arc, Mutex, MutexGuard},
 };
 
-use axum::{extract::{ConnectInfo, Request, State}, middleware, response::Response};
+#[cfg(feature = "website")]
+use {
+    axum::{extract::{ConnectInfo, Request, State}, middleware, response::Response},
+    std::net::SocketAddr,
+};
 
 #[derive(Debug, Default)]
 pub struct OwnedStats {
@@ -34,23 +38,28 @@ impl Stats {
         self.0.lock().expect("failed to get stats")
     }
 
+    #[cfg(feature = "website")]
     pub fn record_website_visitor(&self, addr: IpAddr) {
         self.lock().website_visitors.insert(addr);
     }
 
+    #[cfg(feature = "website")]
     pub fn record_audio_downloader(&self, addr: IpAddr) {
         self.lock().audio_downloaders.insert(addr);
     }
 
+    #[cfg(feature = "website")]
     pub fn record_video_downloader(&self, addr: IpAddr) {
         self.lock().video_downloaders.insert(addr);
     }
 
+    #[cfg(feature = "bot")]
     pub fn record_bot_user(&self, id: u64) {
         self.lock().bot_users.insert(id);
     }
 }
 
+#[cfg(feature = "website")]
 pub async fn record_website_visitor(
     stats: State<Stats>,
     addr: ConnectInfo<SocketAddr>,
@@ -61,6 +70,7 @@ pub async fn record_website_visitor(
     next.run(request).await
 }
 
+#[cfg(feature = "website")]
 pub async fn record_audio_downloader(
     stats: State<Stats>,
     addr: ConnectInfo<SocketAddr>,
@@ -71,6 +81,7 @@ pub async fn record_audio_downloader(
     next.run(request).await
 }
 
+#[cfg(feature = "website")]
 pub async fn record_video_downloader(
     stats: State<Stats>,
     addr: ConnectInfo<SocketAddr>,
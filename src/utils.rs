@@ -34,12 +34,14 @@ pub fn default<T: Default>() -> T {
 ///
 /// Another difference is that if the formatter's buffer is exhausted, the string received from 
 /// [`LimitedFormatter::to_string`] will have its last 3 chars replaced with "..."
+#[cfg(feature = "telegram-logs")]
 #[derive(Clone, Copy)]
 pub struct LimitedFormatter<const CAP: usize> {
     buf: [u8; CAP],
     len: usize,
 }
 
+#[cfg(feature = "telegram-logs")]
 impl<const CAP: usize> std::fmt::Write for LimitedFormatter<CAP> {
     fn write_str(&mut self, s: &str) -> std::fmt::Result {
         let rem = CAP - self.len;
@@ -56,6 +58,7 @@ impl<const CAP: usize> std::fmt::Write for LimitedFormatter<CAP> {
     }
 }
 
+#[cfg(feature = "telegram-logs")]
 impl<const CAP: usize> LimitedFormatter<CAP> {
     pub const fn new() -> Self {
         Self { buf: [0u8; CAP], len: 0 }
@@ -1,60 +1,143 @@
 use {
-    crate::{download::{download, Error, MediaKind}, try_harder_async},
-    axum::{body::Body, http::Uri, response::IntoResponse},
+    crate::{
+        download::{
+            parse_timestamp, zip, Clip, Download, Downloader, Error, MediaKind, Options,
+            OutputFormat,
+        },
+        try_harder_async,
+    },
+    axum::{body::Body, extract::State, http::Uri, response::IntoResponse, Json},
     http::{header::{CONTENT_DISPOSITION, CONTENT_TYPE}, HeaderMap, StatusCode},
     percent_encoding::percent_decode_str,
+    std::sync::Arc,
 };
 
-async fn serve_media(mkind: MediaKind, uri: Uri) -> impl IntoResponse {
-    let res: Result<_, Option<&'static str>> = try_harder_async! {
-        let mut link = None;
-        for pair in uri.query().ok_or("no query parameters provided")?.split('&') {
-            link = pair.strip_prefix("link=");
+/// The status of the response to a request that failed with `err`.
+const fn status(err: &Error) -> StatusCode {
+    match err {
+        Error::InvalidLink | Error::UnsupportedUrl(_) => StatusCode::BAD_REQUEST,
+        Error::IsStream | Error::TooLarge => StatusCode::UNPROCESSABLE_ENTITY,
+        Error::NotFound => StatusCode::NOT_FOUND,
+        Error::Removed(_) => StatusCode::GONE,
+        | Error::Private(_)
+        | Error::AgeRestricted(_)
+        | Error::GeoBlocked(_)
+        | Error::MembersOnly(_)
+        | Error::LoginRequired(_) => StatusCode::FORBIDDEN,
+        Error::Busy => StatusCode::SERVICE_UNAVAILABLE,
+        Error::RateLimited(_) | Error::MetadataFetchFailed | Error::DataFetchFailed => {
+            StatusCode::BAD_GATEWAY
         }
-        let link = percent_decode_str(link.ok_or("`link` query parameter missing")?)
-            .decode_utf8_lossy();
+    }
+}
+
+const fn bad_request(msg: &'static str) -> (StatusCode, &'static str) {
+    (StatusCode::BAD_REQUEST, msg)
+}
+
+fn header_error() -> (StatusCode, &'static str) {
+    log::warn!("String to HeaderValue conversion failed");
+    (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error")
+}
 
-        log::info!("Downloading {mkind:?} from {link:?}");
-        match download(&link, mkind).await {
-            Ok(stream) => {
-                let name = stream.filename();
-                let mime = mkind.mime_type();
+/// Returns the message shown to the user when the download fails.
+const fn describe(err: &Error) -> &'static str {
+    if let Some(reason) = err.reason() {
+        return reason;
+    }
+    match err {
+        Error::TooLarge => "The media is too big",
+        Error::IsStream => "Livestreams can't be downloaded",
+        Error::NotFound => "Invalid video ID, make sure the link is copied & pasted correctly",
+        Error::InvalidLink => "Invalid link, make sure the link is copied & pasted correctly",
+        Error::Busy => "The server is busy, try again in a few minutes",
+        _ => "Server error",
+    }
+}
+
+async fn serve_media(downloader: &Downloader, mkind: MediaKind, uri: Uri) -> impl IntoResponse {
+    let res: Result<_, (StatusCode, &'static str)> = try_harder_async! {
+        let (mut link, mut options) = (None, Options::new(mkind));
+        let (mut start, mut end) = (None, None);
+        for pair in uri.query().ok_or(bad_request("no query parameters provided"))?.split('&') {
+            let Some((key, value)) = pair.split_once('=') else { continue };
+            let value = percent_decode_str(value).decode_utf8_lossy();
+            match key {
+                "link" => link = Some(value),
+                "format" if !value.is_empty() => {
+                    options.format = value.parse()
+                        .ok()
+                        .filter(|f: &OutputFormat| f.kind() == mkind)
+                        .ok_or(bad_request("Invalid `format` query parameter"))?;
+                }
+                "quality" if !value.is_empty() => {
+                    options.quality = value.parse()
+                        .map_err(|()| bad_request("Invalid `quality` query parameter"))?;
+                }
+                "start" if !value.is_empty() => {
+                    let msg = "Invalid `start` query parameter";
+                    start = Some(parse_timestamp(&value).ok_or(bad_request(msg))?);
+                }
+                "end" if !value.is_empty() => {
+                    let msg = "Invalid `end` query parameter";
+                    end = Some(parse_timestamp(&value).ok_or(bad_request(msg))?);
+                }
+                _ => (),
+            }
+        }
+        let link = link.ok_or(bad_request("`link` query parameter missing"))?;
+        options.clip = Clip::new(start, end)
+            .ok_or(bad_request("The clip must end after it starts"))?;
+
+        log::info!("Downloading {link:?} as {options}");
+        match downloader.download(&link, options).await {
+            Ok(download) => {
+                let (name, mime, body) = match download {
+                    Download::Media(media) => (
+                        media.filename().to_owned(),
+                        media.format().unwrap_or(options.format).mime_type(),
+                        Body::from_stream(media),
+                    ),
+                    Download::Playlist(playlist) => (
+                        format!("{}.zip", playlist.title),
+                        "application/zip",
+                        Body::from_stream(zip::stream(playlist)),
+                    ),
+                };
 
                 let content_disposition = format!("attachment; filename=\"{name}\"");
                 let headers = HeaderMap::from_iter([
-                    (CONTENT_TYPE, mime.try_into().map_err(|_| None)?),
-                    (CONTENT_DISPOSITION, content_disposition.try_into().map_err(|_| None)?),
+                    (CONTENT_TYPE, mime.try_into().map_err(|_| header_error())?),
+                    (
+                        CONTENT_DISPOSITION,
+                        content_disposition.try_into().map_err(|_| header_error())?,
+                    ),
                 ]);
-                (StatusCode::OK, headers, Body::from_stream(stream))
-            }
-            Err(Error::TooLarge) => Err("The media is too big")?,
-            Err(Error::IsStream) => Err("Livestreams can't be downloaded")?,
-            Err(Error::NotFound) => {
-                Err("Invalid video ID, make sure the link is copied & pasted correctly")?
-            }
-            Err(Error::InvalidLink) => {
-                Err("Invalid link, make sure the link is copied & pasted correctly")?
-            }
-            Err(Error::DataFetchFailed | Error::MetadataFetchFailed) => {
-                Err("Server error")?
+                (StatusCode::OK, headers, body)
             }
+            Err(err) => Err((status(&err), describe(&err)))?,
         }
     };
+    res
+}
 
-    match res {
-        Ok(x) => Ok(x),
-        Err(Some(msg)) => Err((StatusCode::BAD_REQUEST, msg)),
-        Err(None) => {
-            log::warn!("String to HeaderValue conversion failed");
-            Err((StatusCode::INTERNAL_SERVER_ERROR, "Internal server error"))
-        }
-    }
+/// Responds with the details about the media at the `link` query parameter, as JSON.
+pub async fn serve_info(downloader: State<Arc<Downloader>>, uri: Uri) -> impl IntoResponse {
+    let link = uri.query()
+        .unwrap_or_default()
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("link="))
+        .map(|link| percent_decode_str(link).decode_utf8_lossy())
+        .ok_or((StatusCode::BAD_REQUEST, "`link` query parameter missing"))?;
+    downloader.info(&link).await
+        .map(Json)
+        .map_err(|err| (status(&err), describe(&err)))
 }
 
-pub async fn serve_audio(uri: Uri) -> impl IntoResponse {
-    serve_media(MediaKind::Audio, uri).await
+pub async fn serve_audio(downloader: State<Arc<Downloader>>, uri: Uri) -> impl IntoResponse {
+    serve_media(&downloader, MediaKind::Audio, uri).await
 }
 
-pub async fn serve_video(uri: Uri) -> impl IntoResponse {
-    serve_media(MediaKind::Video, uri).await
+pub async fn serve_video(downloader: State<Arc<Downloader>>, uri: Uri) -> impl IntoResponse {
+    serve_media(&downloader, MediaKind::Video, uri).await
 }
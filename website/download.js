@@ -2,11 +2,11 @@ async function main() {
     let msgElement = document.getElementById("msg")
     let params = new URLSearchParams(window.location.search.substr(1))
     let kind = params.get("kind")
-    let link = params.get("link")
+    params.delete("kind")
 
     try {
         msgElement.innerText = "Fetching..."
-        const res = await fetch(`/${kind}?link=${encodeURIComponent(link)}`)
+        const res = await fetch(`/${kind}?${params}`)
         if (!res.ok) {
             msgElement.innerText = `Error: ${await res.text()}`
             msgElement.className = "error"